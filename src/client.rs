@@ -0,0 +1,492 @@
+use crate::error::{Error, Result};
+use crate::protocol::{ClientMessage, ServerMessage, PROTOCOL_VERSION};
+use crate::socket::is_locked;
+use crate::theme::ThemeState;
+use crate::theme_listener::ThemeListener;
+
+use std::collections::hash_map::RandomState;
+use std::fs::{exists, read_to_string};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long one-shot commands wait for the daemon to reply
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long stop waits for the daemon to exit
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Three missed heartbeats mean the daemon is gone or wedged
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Delay before the first reconnection attempt, doubled after every failure
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(500);
+
+// Longest delay between reconnection attempts
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/**
+ * Exponential backoff with jitter between reconnection attempts.
+ */
+#[derive(Default)]
+struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /**
+     * The delay before the next attempt, doubling with every failed attempt
+     * up to RECONNECT_DELAY_MAX. A random share between half and all of it is
+     * used so listeners cut off together do not reconnect in lockstep.
+     */
+    fn next_delay(&mut self) -> Duration {
+        let delay = RECONNECT_DELAY_MIN
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(RECONNECT_DELAY_MAX);
+        self.attempts = self.attempts.saturating_add(1);
+
+        // Every RandomState is seeded differently, std has no other randomness
+        let random = RandomState::new().build_hasher().finish();
+        delay.mul_f64(0.5 + random as f64 / u64::MAX as f64 / 2.0)
+    }
+}
+
+/**
+ * Applies the theme events of a single connection until it fails. The
+ * daemon starts with the current theme, which re-applies it after a
+ * reconnect.
+ */
+fn follow_stream<A, B>(socket_path: &Path, listener: &A, backoff: &mut Backoff) -> Result<()>
+where
+    A: ThemeListener<B> + Clone,
+{
+    let mut theme_stream = UnixStream::connect(socket_path)
+        .map_err(|error| Error::Ipc("Error connecting to theme server".to_string(), error))?;
+    // Text events only carry the theme, accent color and contrast need JSON
+    let hello = ClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        heartbeat: true,
+    };
+    theme_stream
+        .write_all(format!("{}\n", hello.to_line()).as_bytes())
+        .map_err(|error| Error::Ipc("Error writing to theme server".to_string(), error))?;
+    theme_stream
+        .set_read_timeout(Some(HEARTBEAT_TIMEOUT))
+        .map_err(|error| Error::Ipc("Error configuring theme server socket".to_string(), error))?;
+
+    let mut reader = BufReader::new(theme_stream);
+    let mut welcomed = false;
+
+    loop {
+        let mut content = String::new();
+        let read = reader.read_line(&mut content).map_err(|error| {
+            let message = match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    "No heartbeat from theme server"
+                }
+                _ => "Error reading from theme server",
+            };
+            Error::Ipc(message.to_string(), error)
+        })?;
+        if read == 0 {
+            return Err(Error::Ipc(
+                "Theme server closed the connection".to_string(),
+                io::ErrorKind::UnexpectedEof.into(),
+            ));
+        }
+
+        let message = match content.trim().parse() {
+            Ok(message) => message,
+            // A hello reaching the daemon late follows a plain-text event
+            Err(_) if !welcomed => continue,
+            Err(error) => return Err(error),
+        };
+        let theme_state = match message {
+            ServerMessage::Event {
+                theme,
+                accent_color,
+                contrast,
+                ..
+            } => {
+                let mut theme_state = ThemeState::new(theme.parse()?);
+                theme_state.accent_color = accent_color.map(|color| color.parse()).transpose()?;
+                theme_state.contrast = contrast.parse()?;
+                theme_state
+            }
+            ServerMessage::Welcome { .. } => {
+                welcomed = true;
+                continue;
+            }
+            ServerMessage::Heartbeat => continue,
+            ServerMessage::Error { message } => return Err(Error::Daemon(message)),
+            ServerMessage::Shutdown => {
+                return Err(Error::Daemon("Theme server shut down".to_string()))
+            }
+        };
+        // A failing listener should not stop following theme changes
+        if let Err(error) = listener.clone().handle(theme_state) {
+            println!("WARNING: {error}");
+        }
+        backoff.reset();
+    }
+}
+
+/**
+ * Applies every theme event to the listener, connecting again with backoff
+ * whenever the daemon is not running, goes away or misses its heartbeats.
+ * @param socket_path The daemon socket
+ * @param listener Applies the themes
+ */
+pub fn handle_stream<A, B>(socket_path: &Path, listener: A) -> Result<()>
+where
+    A: ThemeListener<B> + Clone,
+{
+    let mut backoff = Backoff::default();
+    loop {
+        if let Err(error) = follow_stream(socket_path, &listener, &mut backoff) {
+            let delay = backoff.next_delay();
+            println!(
+                "WARNING: {error}, reconnecting in {:.1}s",
+                delay.as_secs_f64()
+            );
+            thread::sleep(delay);
+        }
+    }
+}
+
+/**
+ * Sends a one-shot command to the daemon and returns its reply line.
+ * @param socket_path The daemon socket
+ * @param command The command line, e.g. "set dark 2h"
+ */
+pub fn send_command(socket_path: &Path, command: &str) -> Result<String> {
+    let mut theme_stream = UnixStream::connect(socket_path)
+        .map_err(|error| Error::Ipc("Error connecting to theme server".to_string(), error))?;
+    theme_stream
+        .set_read_timeout(Some(COMMAND_TIMEOUT))
+        .map_err(|error| Error::Ipc("Error configuring theme server socket".to_string(), error))?;
+    theme_stream
+        .write_all(format!("{command}\n").as_bytes())
+        .map_err(|error| Error::Ipc("Error writing to theme server".to_string(), error))?;
+
+    let mut reply = String::new();
+    BufReader::new(theme_stream)
+        .read_line(&mut reply)
+        .map_err(|error| Error::Ipc("Error reading from theme server".to_string(), error))?;
+
+    let reply = reply.trim();
+    match reply.strip_prefix("error ") {
+        Some(message) => Err(Error::Daemon(message.to_string())),
+        None => Ok(reply.to_string()),
+    }
+}
+
+/**
+ * Probes the socket with a ping. Only a refused connection or a missing
+ * socket counts as no daemon, a daemon that is slow to reply still owns it.
+ * @param socket_path The daemon socket
+ */
+pub fn is_daemon_running(socket_path: &Path) -> bool {
+    match send_command(socket_path, "ping") {
+        Err(Error::Ipc(_, error)) => !matches!(
+            error.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+        ),
+        _ => true,
+    }
+}
+
+/**
+ * Reads the daemon PID from its pidfile, None when it is missing or invalid.
+ */
+fn read_pid(pid_path: &Path) -> Option<libc::pid_t> {
+    read_to_string(pid_path).ok()?.trim().parse().ok()
+}
+
+fn is_process_running(pid: libc::pid_t) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/**
+ * Finds a key=value attribute in a reply line, e.g. pid in a status reply.
+ */
+fn reply_attribute<'a>(reply: &'a str, key: &str) -> Option<&'a str> {
+    reply
+        .split_whitespace()
+        .filter_map(|part| part.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+/**
+ * Asks the daemon for its status and formats it for the terminal.
+ * @param socket_path The daemon socket
+ */
+pub fn daemon_status(socket_path: &Path) -> Result<String> {
+    if !is_daemon_running(socket_path) {
+        return Err(Error::Daemon("Daemon is not running".to_string()));
+    }
+
+    let reply = send_command(socket_path, "status")?;
+    let theme_state: ThemeState = reply.parse()?;
+    let attribute = |key: &str| reply_attribute(&reply, key).unwrap_or("unknown");
+
+    let mut status = format!(
+        "theme-listener is running\npid: {}\nuptime: {}s\ntheme: {}\ncontrast: {}",
+        attribute("pid"),
+        attribute("uptime"),
+        theme_state.theme,
+        theme_state.contrast
+    );
+    if let Some(accent_color) = theme_state.accent_color {
+        status.push_str(&format!("\naccent color: {accent_color}"));
+    }
+    status.push_str(&format!("\nclients: {}", attribute("clients")));
+    if reply.contains(" override=") {
+        status.push_str(&format!("\noverride: {}", attribute("override")));
+        if reply.contains(" expires-in=") {
+            status.push_str(&format!(" (expires in {}s)", attribute("expires-in")));
+        }
+    }
+    Ok(status)
+}
+
+/**
+ * Stops the daemon over the socket, falling back to SIGTERM on the PID from
+ * the pidfile when the socket does not answer, and waits for it to exit.
+ * @param socket_path The daemon socket
+ * @param pid_path The daemon pidfile
+ * @return Whether a daemon was running
+ */
+pub fn stop_daemon(socket_path: &Path, pid_path: &Path) -> Result<bool> {
+    // Daemons started with -init hold the socket lock until they exit
+    let locked = is_locked(socket_path);
+    // Socket activated daemons have neither lock nor pidfile but report
+    // their PID, their socket stays with systemd after they exit
+    let reported_pid = send_command(socket_path, "status")
+        .ok()
+        .and_then(|reply| reply_attribute(&reply, "pid")?.parse().ok());
+    // After a crash the PID may have been reused by an unrelated process,
+    // so the pidfile is only trusted while a daemon holds the lock
+    let pid = reported_pid
+        .or_else(|| read_pid(pid_path).filter(|_| locked))
+        .filter(|pid| is_process_running(*pid));
+
+    if send_command(socket_path, "stop").is_err() {
+        let Some(pid) = pid.filter(|_| locked) else {
+            return Ok(false);
+        };
+        if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+            return Err(Error::Ipc(
+                format!("Error signalling daemon {pid}"),
+                io::Error::last_os_error(),
+            ));
+        }
+    }
+
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while Instant::now() < deadline {
+        let stopped = match pid {
+            Some(pid) => !is_process_running(pid),
+            None if locked => !is_locked(socket_path),
+            None => !exists(socket_path).unwrap_or(false),
+        };
+        if stopped {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Err(Error::Daemon("Daemon did not stop in time".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::theme::{Contrast, Theme};
+    use std::io::Read;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    /**
+     * Records the theme states it is asked to apply.
+     */
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<ThemeState>>>);
+
+    impl ThemeListener<()> for Recorder {
+        fn handle(self, next_theme_state: ThemeState) -> Result<()> {
+            self.0.lock().unwrap().push(next_theme_state);
+            Ok(())
+        }
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("theme-listener-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /**
+     * Accepts one client, reads its hello and writes the given lines.
+     */
+    fn serve(socket_path: &Path, lines: &'static str) -> thread::JoinHandle<String> {
+        let listener = UnixListener::bind(socket_path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut hello = [0; 256];
+            let read = stream.read(&mut hello).unwrap();
+            stream.write_all(lines.as_bytes()).unwrap();
+            String::from_utf8_lossy(&hello[..read]).into_owned()
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_maximum() {
+        let mut backoff = Backoff::default();
+        let mut delays = Vec::new();
+        for _ in 0..10 {
+            delays.push(backoff.next_delay());
+        }
+        assert!(delays[0] >= RECONNECT_DELAY_MIN / 2 && delays[0] <= RECONNECT_DELAY_MIN);
+        assert!(delays[1] >= RECONNECT_DELAY_MIN && delays[1] <= RECONNECT_DELAY_MIN * 2);
+        assert!(delays
+            .iter()
+            .all(|delay| *delay >= RECONNECT_DELAY_MIN / 2 && *delay <= RECONNECT_DELAY_MAX));
+        assert!(delays[9] >= RECONNECT_DELAY_MAX / 2);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= RECONNECT_DELAY_MIN);
+    }
+
+    #[test]
+    fn follow_stream_applies_events() {
+        let _env = crate::lock_env();
+        let socket_path = socket_path("follow");
+        let server = serve(
+            &socket_path,
+            concat!(
+                r#"{"type":"welcome","version":1,"capabilities":[]}"#,
+                "\n",
+                r##"{"type":"event","sequence":1,"timestamp":0,"source":"startup","theme":"dark","accent_color":"#3584e4","contrast":"high"}"##,
+                "\n",
+                r#"{"type":"heartbeat"}"#,
+                "\n",
+                r#"{"type":"shutdown"}"#,
+                "\n",
+            ),
+        );
+
+        let recorder = Recorder::default();
+        let mut backoff = Backoff { attempts: 3 };
+        let result = follow_stream(&socket_path, &recorder, &mut backoff);
+        assert!(
+            matches!(result, Err(Error::Daemon(message)) if message == "Theme server shut down")
+        );
+
+        let hello: ClientMessage = server.join().unwrap().trim().parse().unwrap();
+        assert!(matches!(
+            hello,
+            ClientMessage::Hello {
+                heartbeat: true,
+                ..
+            }
+        ));
+        let mut expected = ThemeState::new(Theme::Dark);
+        expected.accent_color = "#3584e4".parse().ok();
+        expected.contrast = Contrast::High;
+        assert_eq!(*recorder.0.lock().unwrap(), [expected]);
+        // An applied event resets the backoff
+        assert_eq!(backoff.attempts, 0);
+        let _ = std::fs::remove_file(socket_path);
+    }
+
+    #[test]
+    fn follow_stream_skips_text_before_welcome() {
+        let _env = crate::lock_env();
+        let socket_path = socket_path("late-hello");
+        // The daemon took the client for a legacy subscriber before its hello
+        let server = serve(
+            &socket_path,
+            concat!(
+                "light\n",
+                r#"{"type":"welcome","version":1,"capabilities":[]}"#,
+                "\n",
+                r#"{"type":"event","sequence":1,"timestamp":0,"source":"startup","theme":"dark","contrast":"normal"}"#,
+                "\n",
+                "light\n",
+            ),
+        );
+
+        let recorder = Recorder::default();
+        let result = follow_stream(&socket_path, &recorder, &mut Backoff::default());
+        server.join().unwrap();
+        assert!(matches!(result, Err(Error::Parse(_))));
+        assert_eq!(*recorder.0.lock().unwrap(), [ThemeState::new(Theme::Dark)]);
+        let _ = std::fs::remove_file(socket_path);
+    }
+
+    #[test]
+    fn follow_stream_detects_closed_connection() {
+        let _env = crate::lock_env();
+        let socket_path = socket_path("closed");
+        let server = serve(&socket_path, "");
+
+        let mut backoff = Backoff::default();
+        let result = follow_stream(&socket_path, &Recorder::default(), &mut backoff);
+        server.join().unwrap();
+        assert!(
+            matches!(result, Err(Error::Ipc(_, error)) if error.kind() == io::ErrorKind::UnexpectedEof)
+        );
+        let _ = std::fs::remove_file(socket_path);
+    }
+
+    #[test]
+    fn reply_attributes() {
+        let reply = "dark sequence=2 source=portal pid=42 uptime=7 clients=1";
+        assert_eq!(reply_attribute(reply, "pid"), Some("42"));
+        assert_eq!(reply_attribute(reply, "clients"), Some("1"));
+        assert_eq!(reply_attribute(reply, "override"), None);
+    }
+
+    #[test]
+    fn stop_socket_activated_daemon() {
+        let _env = crate::lock_env();
+        let socket_path = socket_path("activated");
+        // Stands in for the daemon, systemd keeps the socket after it exits
+        let mut daemon = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let daemon_pid = daemon.id();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = thread::spawn(move || {
+            for mut stream in listener.incoming().flatten().take(2) {
+                let mut command = String::new();
+                BufReader::new(&stream).read_line(&mut command).unwrap();
+                let reply = match command.trim() {
+                    "status" => format!("dark sequence=1 pid={daemon_pid} uptime=1 clients=0"),
+                    _ => "stopping".to_string(),
+                };
+                stream.write_all(format!("{reply}\n").as_bytes()).unwrap();
+            }
+            daemon.kill().unwrap();
+            daemon.wait().unwrap();
+        });
+
+        let pid_path = socket_path.with_extension("pid");
+        assert!(stop_daemon(&socket_path, &pid_path).unwrap());
+        server.join().unwrap();
+        assert!(exists(&socket_path).unwrap());
+        let _ = std::fs::remove_file(socket_path);
+    }
+}
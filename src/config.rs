@@ -0,0 +1,486 @@
+use crate::daemon::SlowClientPolicy;
+use crate::error::{Error, Result};
+use crate::theme_listener::LISTENER_NAMES;
+use crate::theme_publisher::PUBLISHER_NAME;
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_to_string};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Change notifications specific to os
+cfg_if::cfg_if!(
+    if #[cfg(target_os = "linux")] {
+        use std::collections::HashSet;
+        use std::fs::File;
+        use std::io::Read;
+        use std::os::fd::{AsRawFd, FromRawFd};
+        use std::os::unix::ffi::OsStrExt;
+
+        // Events on the config file and its directory that cause a reload
+        const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_DELETE;
+
+        /**
+         * Watches the directory of a file for changes to it.
+         * @param watched The file names of every watched directory
+         */
+        fn add_watch(
+            inotify: &File,
+            watched: &mut HashMap<libc::c_int, HashSet<Vec<u8>>>,
+            path: &Path,
+        ) -> Result<()> {
+            let watch_error =
+                |error: io::Error| Error::Ipc(format!("Error watching {}", path.display()), error);
+            let (Some(directory), Some(file_name)) = (path.parent(), path.file_name()) else {
+                return Err(Error::Config(format!("Invalid config path {}", path.display())));
+            };
+            let directory = std::ffi::CString::new(directory.as_os_str().as_bytes())
+                .map_err(|error| watch_error(error.into()))?;
+            let watch =
+                unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), directory.as_ptr(), WATCH_MASK) };
+            if watch == -1 {
+                return Err(watch_error(io::Error::last_os_error()));
+            }
+            watched
+                .entry(watch)
+                .or_default()
+                .insert(file_name.as_bytes().to_vec());
+            Ok(())
+        }
+
+        /**
+         * Calls on_change whenever the file is written, replaced or removed.
+         * The directory is watched as editors tend to replace the file
+         * instead of writing to it. For a symlink the directory of its target
+         * is watched as well, as editing the target leaves the link alone.
+         */
+        fn watch_file<F: FnMut()>(path: &Path, mut on_change: F) -> Result<()> {
+            let watch_error =
+                |error: io::Error| Error::Ipc(format!("Error watching {}", path.display()), error);
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd == -1 {
+                return Err(watch_error(io::Error::last_os_error()));
+            }
+            let mut inotify = unsafe { File::from_raw_fd(fd) };
+            let mut watched = HashMap::new();
+            add_watch(&inotify, &mut watched, path)?;
+            let mut target = path.canonicalize().ok();
+            if let Some(target) = &target {
+                add_watch(&inotify, &mut watched, target)?;
+            }
+
+            let event_size = std::mem::size_of::<libc::inotify_event>();
+            let mut buffer = [0u8; 4096];
+            loop {
+                let length = inotify.read(&mut buffer).map_err(watch_error)?;
+                // A single read may hold several events, they cause one reload
+                let mut changed = false;
+                let mut offset = 0;
+                while offset + event_size <= length {
+                    let event = unsafe {
+                        std::ptr::read_unaligned(
+                            buffer[offset..].as_ptr().cast::<libc::inotify_event>(),
+                        )
+                    };
+                    let name_start = offset + event_size;
+                    offset = (name_start + event.len as usize).min(length);
+                    // Names are padded with NUL bytes
+                    let name = buffer[name_start..offset].split(|byte| *byte == 0).next();
+                    changed |= name.is_some_and(|name| {
+                        watched
+                            .get(&event.wd)
+                            .is_some_and(|file_names| file_names.contains(name))
+                    });
+                }
+                if changed {
+                    // The link may lead somewhere else now
+                    let next_target = path.canonicalize().ok();
+                    if next_target != target {
+                        if let Some(next_target) = &next_target {
+                            add_watch(&inotify, &mut watched, next_target)?;
+                        }
+                        target = next_target;
+                    }
+                    on_change();
+                }
+            }
+        }
+    } else if #[cfg(target_os = "macos")] {
+        use std::fs::metadata;
+        use std::time::Duration;
+
+        // How often the modification time of the config file is checked
+        const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+        /**
+         * Calls on_change whenever the modification time of the file changes,
+         * including when it is created or removed.
+         */
+        fn watch_file<F: FnMut()>(path: &Path, mut on_change: F) -> Result<()> {
+            let modified = || metadata(path).and_then(|metadata| metadata.modified()).ok();
+            let mut last_modified = modified();
+            loop {
+                std::thread::sleep(WATCH_INTERVAL);
+                let current_modified = modified();
+                if current_modified != last_modified {
+                    last_modified = current_modified;
+                    on_change();
+                }
+            }
+        }
+    }
+);
+
+/**
+ * The config file read on startup and reloaded by the daemon.
+ */
+#[derive(Clone, Debug)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// Whether a missing file means the defaults, only when not passed with -config
+    optional: bool,
+}
+
+impl ConfigFile {
+    /**
+     * Resolves the config file location. The -config flag wins over
+     * $XDG_CONFIG_HOME/theme-listener/config.toml, which falls back to
+     * $HOME/.config when XDG_CONFIG_HOME is not set.
+     * @param config_flag The value passed with -config, if any
+     */
+    pub fn new(config_flag: Option<PathBuf>) -> ConfigFile {
+        if let Some(path) = config_flag {
+            return ConfigFile {
+                path,
+                optional: false,
+            };
+        }
+        let config_directory = match std::env::var("XDG_CONFIG_HOME") {
+            Ok(config_home) if !config_home.is_empty() => PathBuf::from(config_home),
+            _ => expand_home(PathBuf::from("~/.config")),
+        };
+        ConfigFile {
+            path: config_directory.join("theme-listener/config.toml"),
+            optional: true,
+        }
+    }
+
+    /**
+     * Reads and validates the config file.
+     */
+    pub fn load(&self) -> Result<Config> {
+        let content = match read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(error) if self.optional && error.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            Err(error) => {
+                return Err(Error::Ipc(
+                    format!("Error reading config {}", self.path.display()),
+                    error,
+                ))
+            }
+        };
+        let mut config: Config = toml::from_str(&content).map_err(|error| {
+            Error::Config(format!("Invalid config {}: {error}", self.path.display()))
+        })?;
+
+        config.socket = config.socket.map(expand_home);
+        config.alacritty.socket = config.alacritty.socket.map(expand_home);
+        for theme_files in [
+            &mut config.alacritty.theme_files,
+            &mut config.tmux.theme_files,
+        ] {
+            theme_files.directory = theme_files.directory.take().map(expand_home);
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /**
+     * Blocks calling on_change whenever the config file changes. A missing
+     * config directory is created so a file written later is picked up.
+     * @param on_change Reloads the config
+     */
+    pub fn watch<F: FnMut()>(&self, on_change: F) -> Result<()> {
+        if let Some(directory) = self.path.parent() {
+            create_dir_all(directory).map_err(|error| {
+                Error::Ipc(format!("Error creating {}", directory.display()), error)
+            })?;
+        }
+        watch_file(&self.path, on_change)
+    }
+}
+
+/**
+ * Replaces a leading ~ with the home directory.
+ */
+fn expand_home(path: PathBuf) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(relative_path) => {
+            PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(relative_path)
+        }
+        Err(_) => path,
+    }
+}
+
+/**
+ * Theme files of a listener. Variants are keyed by the theme they apply
+ * to, e.g. light, dark or a named theme, and name a file in the directory
+ * without its extension.
+ */
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ThemeFilesConfig {
+    /// The directory holding the theme files
+    pub directory: Option<PathBuf>,
+    /// Theme applied when the system reports no preference, light or dark
+    pub no_preference: Option<String>,
+    pub themes: HashMap<String, String>,
+    /// Files used with high contrast, falling back to themes
+    pub high_contrast_themes: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct AlacrittyConfig {
+    /// The Alacritty IPC socket
+    pub socket: Option<PathBuf>,
+    /// Color keys to override with the accent color, e.g. cursor.cursor
+    pub accent_colors: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub theme_files: ThemeFilesConfig,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TmuxConfig {
+    #[serde(flatten)]
+    pub theme_files: ThemeFilesConfig,
+}
+
+/**
+ * Settings read from config.toml. Flags and environment variables take
+ * precedence over every value set here.
+ */
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Config {
+    /// The daemon socket
+    pub socket: Option<PathBuf>,
+    /// The publisher reporting the OS theme, only one exists per platform
+    pub publisher: Option<String>,
+    /// What the daemon does with subscribers that stop reading
+    pub slow_clients: Option<String>,
+    /// What toggle and text subscribers treat a "no preference" theme as,
+    /// light or dark
+    pub no_preference: Option<String>,
+    /// Listeners used when no listener flag is passed
+    pub listeners: Vec<String>,
+    /// Whether the daemon runs the listeners instead of the -d client
+    pub host_listeners: bool,
+    pub alacritty: AlacrittyConfig,
+    pub tmux: TmuxConfig,
+}
+
+impl Config {
+    /**
+     * Whether the listeners would apply themes differently with the other
+     * config.
+     */
+    pub fn listeners_differ(&self, other: &Config) -> bool {
+        self.listeners != other.listeners
+            || self.host_listeners != other.host_listeners
+            || self.alacritty != other.alacritty
+            || self.tmux != other.tmux
+    }
+
+    /**
+     * Checks the values serde cannot, the listeners validate their theme
+     * files once they are created.
+     */
+    fn validate(&self) -> Result<()> {
+        if let Some(publisher) = &self.publisher {
+            if publisher != PUBLISHER_NAME {
+                return Err(Error::Config(format!(
+                    "Unknown publisher {publisher:?}, expected {PUBLISHER_NAME}"
+                )));
+            }
+        }
+        if let Some(slow_clients) = &self.slow_clients {
+            slow_clients.parse::<SlowClientPolicy>()?;
+        }
+        if let Some(no_preference) = &self.no_preference {
+            if !["light", "dark"].contains(&no_preference.as_str()) {
+                return Err(Error::Config(
+                    "no_preference must be either light or dark".to_string(),
+                ));
+            }
+        }
+        for listener in &self.listeners {
+            if !LISTENER_NAMES.contains(&listener.as_str()) {
+                return Err(Error::Config(format!(
+                    "Unknown listener {listener:?}, expected one of {}",
+                    LISTENER_NAMES.join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config> {
+        let config: Config =
+            toml::from_str(content).map_err(|error| Error::Config(error.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn config_file(name: &str, content: Option<&str>) -> ConfigFile {
+        let path =
+            std::env::temp_dir().join(format!("theme-listener-{}-{name}.toml", std::process::id()));
+        match content {
+            Some(content) => std::fs::write(&path, content).unwrap(),
+            None => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        ConfigFile {
+            path,
+            optional: false,
+        }
+    }
+
+    #[test]
+    fn validate_config() {
+        let config = parse(&format!(
+            r#"
+            publisher = "{PUBLISHER_NAME}"
+            slow_clients = "drop"
+            no_preference = "dark"
+            listeners = ["alacritty", "tmux"]
+            host_listeners = true
+
+            [tmux.themes]
+            dark = "dark-default"
+            dim = "dim"
+
+            [alacritty.high_contrast_themes]
+            sepia = "sepia-hc"
+            "#
+        ))
+        .unwrap();
+        assert_eq!(config.listeners, ["alacritty", "tmux"]);
+        assert!(config.host_listeners);
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn validate_invalid_config() {
+        for content in [
+            r#"publisher = "unknown""#,
+            r#"slow_clients = "block""#,
+            r#"no_preference = "dim""#,
+            r#"listeners = ["kitty"]"#,
+            r#"listeners = "tmux""#,
+            r#"socket = 1"#,
+            "socket =",
+        ] {
+            assert!(
+                matches!(parse(content), Err(Error::Config(_))),
+                "{content:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn load_config_file() {
+        let _env = crate::lock_env();
+        let file = config_file("load", Some("socket = \"~/theme.sock\"\n"));
+        let config = file.load().unwrap();
+        let home = std::env::var("HOME").unwrap_or_default();
+        assert_eq!(config.socket, Some(PathBuf::from(home).join("theme.sock")));
+
+        let invalid = config_file("invalid", Some("listeners = [\"kitty\"]\n"));
+        assert!(matches!(invalid.load(), Err(Error::Config(_))));
+        let _ = std::fs::remove_file(file.path);
+        let _ = std::fs::remove_file(invalid.path);
+    }
+
+    #[test]
+    fn load_missing_config_file() {
+        let _env = crate::lock_env();
+        let mut file = config_file("missing", None);
+        assert!(matches!(file.load(), Err(Error::Ipc(..))));
+        file.optional = true;
+        assert_eq!(file.load().unwrap(), Config::default());
+    }
+
+    /**
+     * Watches the config file on a thread of its own until it reports a
+     * change, rewriting the file until the watch picked it up.
+     */
+    fn wait_for_change(file: ConfigFile, write: impl Fn()) {
+        let (sender, changed) = std::sync::mpsc::channel();
+        let watched = file.clone();
+        std::thread::spawn(move || {
+            let _ = watched.watch(|| {
+                let _ = sender.send(());
+            });
+        });
+        for _ in 0..100 {
+            if file.path.parent().is_some_and(Path::exists) {
+                write();
+            }
+            if changed
+                .recv_timeout(std::time::Duration::from_millis(100))
+                .is_ok()
+            {
+                return;
+            }
+        }
+        panic!("{} change not noticed", file.path.display());
+    }
+
+    #[test]
+    fn watch_created_config_directory() {
+        let _env = crate::lock_env();
+        let directory =
+            std::env::temp_dir().join(format!("theme-listener-{}-watch", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let file = ConfigFile {
+            path: directory.join("theme-listener/config.toml"),
+            optional: true,
+        };
+        let path = file.path.clone();
+        wait_for_change(file, || std::fs::write(&path, "").unwrap());
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn watch_symlinked_config() {
+        let _env = crate::lock_env();
+        let directory =
+            std::env::temp_dir().join(format!("theme-listener-{}-symlink", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(directory.join("dotfiles")).unwrap();
+        let target = directory.join("dotfiles/theme-listener.toml");
+        std::fs::write(&target, "").unwrap();
+        let path = directory.join("config.toml");
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        let file = ConfigFile {
+            path,
+            optional: false,
+        };
+        // Editors write to the target, the link stays untouched
+        wait_for_change(file, || std::fs::write(&target, "").unwrap());
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
@@ -0,0 +1,1511 @@
+use crate::error::{Error, Result};
+use crate::protocol::{ClientMessage, Command, Protocol, ServerMessage, HEARTBEAT_INTERVAL};
+use crate::socket::{is_authorized, peer_pid};
+use crate::theme::{EventSource, Theme, ThemeEvent, ThemeState, ThemeUpdate};
+use crate::theme_listener::{ThemeListener, Variants};
+use crate::theme_publisher::ThemePublisher;
+
+use std::collections::VecDeque;
+use std::fs::remove_file;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+// Threads and communication
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// How long a new client has to send its first line before it is treated as
+// a legacy subscriber
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Longest first line a client may send
+const MAX_LINE_LENGTH: usize = 4096;
+
+// Number of recent events kept for subscribers that fell behind
+const EVENT_HISTORY: usize = 32;
+
+// How long a subscriber may leave its socket unread before it is dropped
+const SLOW_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Most output queued for a subscriber before it is dropped
+const MAX_OUTBOUND: usize = 64 * 1024;
+
+// Longest the event loop waits for an override to expire, the poll timeout
+// stops while the system is suspended
+const OVERRIDE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Clock that keeps running while the system is suspended, unlike Instant
+cfg_if::cfg_if!(
+    if #[cfg(target_os = "linux")] {
+        const SUSPEND_AWARE_CLOCK: libc::clockid_t = libc::CLOCK_BOOTTIME;
+    } else if #[cfg(target_os = "macos")] {
+        const SUSPEND_AWARE_CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+    }
+);
+
+/**
+ * The current time on the suspend aware clock, so an override ends on time
+ * even when the system slept through its expiry.
+ */
+fn suspend_aware_now() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(SUSPEND_AWARE_CLOCK, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/**
+ * What happens to subscribers that stop reading their socket.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Disconnect the subscriber once it stalled for too long
+    Drop,
+    /// Keep only the latest event until the subscriber reads again
+    #[default]
+    Coalesce,
+}
+
+/**
+ * To convert the -slow-clients value to SlowClientPolicy
+ */
+impl FromStr for SlowClientPolicy {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<SlowClientPolicy> {
+        match value {
+            "drop" => Ok(SlowClientPolicy::Drop),
+            "coalesce" => Ok(SlowClientPolicy::Coalesce),
+            _ => Err(Error::Config(format!(
+                "Invalid slow client policy {value:?}, expected drop or coalesce"
+            ))),
+        }
+    }
+}
+
+/**
+ * A theme forced by a client that takes precedence over the OS theme until
+ * it is cleared or expires.
+ */
+#[derive(Clone, Debug)]
+struct ManualOverride {
+    theme: Theme,
+    /// When the override ends on the suspend aware clock
+    expires: Option<Duration>,
+}
+
+/**
+ * The inputs the current theme state is derived from.
+ */
+struct ThemeSources {
+    /// Latest state reported by the OS publisher
+    system: ThemeState,
+    /// Theme forced from the CLI, if any
+    manual: Option<ManualOverride>,
+}
+
+impl ThemeSources {
+    fn state(&self) -> ThemeState {
+        let mut state = self.system.clone();
+        if let Some(manual) = &self.manual {
+            state.theme = manual.theme.clone();
+        }
+        state
+    }
+}
+
+struct DaemonTheme {
+    event: ThemeEvent,
+    sources: ThemeSources,
+    /// What a "no preference" theme resolves to when toggling and for text
+    /// subscribers
+    no_preference: Theme,
+    /// The named themes the listeners have theme files for, the only ones
+    /// clients may set
+    variants: Variants,
+    /// The latest events, oldest first and ending with the current one
+    history: VecDeque<ThemeEvent>,
+}
+
+/**
+ * State shared between the publisher and the event loop serving the clients.
+ */
+pub struct DaemonState {
+    theme: Mutex<DaemonTheme>,
+    started: Instant,
+    clients: AtomicUsize,
+    stopping: AtomicBool,
+    /// Written to whenever the event loop has something new to handle
+    wake_sender: UnixStream,
+    wake_receiver: UnixStream,
+    /// Hands every new state to the listeners hosted in the daemon
+    listener_sender: OnceLock<Sender<ThemeState>>,
+}
+
+impl DaemonState {
+    /**
+     * @param theme_event The theme reported by the publisher on startup
+     * @param no_preference What a "no preference" theme resolves to
+     * @param variants The named themes clients may set
+     */
+    pub fn new(
+        theme_event: ThemeEvent,
+        no_preference: Theme,
+        variants: Variants,
+    ) -> Result<DaemonState> {
+        let (wake_sender, wake_receiver) = UnixStream::pair()
+            .and_then(|(sender, receiver)| {
+                sender.set_nonblocking(true)?;
+                receiver.set_nonblocking(true)?;
+                Ok((sender, receiver))
+            })
+            .map_err(|error| Error::Ipc("Error creating event loop wake-up".to_string(), error))?;
+
+        let sources = ThemeSources {
+            system: theme_event.state.clone(),
+            manual: None,
+        };
+        Ok(DaemonState {
+            theme: Mutex::new(DaemonTheme {
+                history: VecDeque::from([theme_event.clone()]),
+                event: theme_event,
+                sources,
+                no_preference,
+                variants,
+            }),
+            started: Instant::now(),
+            clients: AtomicUsize::new(0),
+            stopping: AtomicBool::new(false),
+            wake_sender,
+            wake_receiver,
+            listener_sender: OnceLock::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DaemonTheme> {
+        self.theme.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /**
+     * Wakes the event loop up. A full socket already holds a pending
+     * wake-up, so failed writes are ignored.
+     */
+    fn wake(&self) {
+        let _ = (&self.wake_sender).write(&[1]);
+    }
+
+    /**
+     * One line summary of the daemon for service managers.
+     */
+    pub fn summary(&self) -> String {
+        let theme = self.lock();
+        let mut summary = format!(
+            "Theme {}, {} clients",
+            theme.event.state.theme,
+            self.clients.load(Ordering::SeqCst)
+        );
+        if theme.sources.manual.is_some() {
+            summary.push_str(", overridden");
+        }
+        summary
+    }
+
+    /**
+     * Changes the theme sources and wakes up the event loop when the
+     * resulting state produced a new event.
+     * @param source Where the change came from
+     * @param update Changes the theme sources
+     * @return The event after the change
+     */
+    fn update<F>(&self, source: EventSource, update: F) -> ThemeEvent
+    where
+        F: FnOnce(&mut ThemeSources),
+    {
+        let mut theme = self.lock();
+        update(&mut theme.sources);
+        let next_state = theme.sources.state();
+        // Updates may report settings that did not change
+        if theme.event.update(next_state, source) {
+            let event = theme.event.clone();
+            if theme.history.len() == EVENT_HISTORY {
+                theme.history.pop_front();
+            }
+            theme.history.push_back(event);
+            self.wake();
+            if let Some(listener_sender) = self.listener_sender.get() {
+                let _ = listener_sender.send(theme.event.state.clone());
+            }
+        }
+        theme.event.clone()
+    }
+
+    /**
+     * Applies the current theme and every later change with a listener
+     * hosted in the daemon. It runs on a thread of its own, so a slow
+     * listener holds up neither the publisher nor the clients.
+     * @param listener Applies the themes, e.g. Listeners. It may be replaced
+     * while the daemon runs
+     */
+    pub fn host_listener<A, B>(&self, listener: Arc<Mutex<A>>) -> Result<()>
+    where
+        A: ThemeListener<B> + Clone + Send + 'static,
+    {
+        let (listener_sender, listener_receiver) = mpsc::channel();
+        // Holding the lock keeps changes from slipping in before the sender is set
+        let theme = self.lock();
+        self.listener_sender
+            .set(listener_sender)
+            .map_err(|_| Error::Daemon("Daemon already hosts a listener".to_string()))?;
+        let current_state = theme.event.state.clone();
+        drop(theme);
+
+        thread::spawn(move || {
+            for theme_state in std::iter::once(current_state).chain(&listener_receiver) {
+                // Only the latest of the changes queued meanwhile is applied
+                let theme_state = listener_receiver.try_iter().last().unwrap_or(theme_state);
+                let listener = listener
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+                if let Err(error) = listener.handle(theme_state) {
+                    println!("WARNING: {error}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /**
+     * Changes what a "no preference" theme resolves to and the named themes
+     * clients may set, e.g. after the config changed.
+     */
+    pub fn set_themes(&self, no_preference: Theme, variants: Variants) {
+        let mut theme = self.lock();
+        theme.no_preference = no_preference;
+        theme.variants = variants;
+    }
+
+    /**
+     * Applies the current theme with the hosted listener again, e.g. after
+     * it was replaced.
+     */
+    pub fn reapply(&self) {
+        let theme = self.lock();
+        if let Some(listener_sender) = self.listener_sender.get() {
+            let _ = listener_sender.send(theme.event.state.clone());
+        }
+    }
+
+    /**
+     * Forces a theme until the given duration passed, the event loop clears
+     * it once it expired.
+     * @param theme The theme to apply
+     * @param duration How long the override lasts, forever when None
+     */
+    fn set_override(&self, theme: Theme, duration: Option<Duration>) -> Result<ThemeEvent> {
+        let expires = match duration {
+            Some(duration) => Some(suspend_aware_now().checked_add(duration).ok_or_else(|| {
+                Error::Parse(format!("override duration {duration:?} is too long"))
+            })?),
+            None => None,
+        };
+        let theme_event = self.update(EventSource::Override, |sources| {
+            sources.manual = Some(ManualOverride { theme, expires });
+        });
+        // The event loop waits for the new expiry even when the theme stayed
+        self.wake();
+        Ok(theme_event)
+    }
+
+    /**
+     * Clears the override once it expired.
+     * @return How long until the current override expires, None when it
+     * lasts forever or there is none
+     */
+    fn expire_override(&self) -> Option<Duration> {
+        let expires = self.lock().sources.manual.as_ref()?.expires?;
+        let now = suspend_aware_now();
+        if expires > now {
+            return Some(expires - now);
+        }
+        self.update(EventSource::Override, |sources| {
+            // Leave an override set meanwhile alone
+            let manual = sources.manual.as_ref();
+            if manual.is_some_and(|manual| manual.expires == Some(expires)) {
+                sources.manual = None;
+            }
+        });
+        None
+    }
+}
+
+pub fn listen_theme<A, B>(publisher: A, state: Arc<DaemonState>) -> Result<()>
+where
+    A: ThemePublisher<B>,
+{
+    publisher.on_publish(Box::new(move |update: ThemeUpdate| {
+        state.update(EventSource::Portal, |sources| sources.system.apply(update));
+    }))
+}
+
+/**
+ * What the event loop does with a connected client.
+ */
+enum ClientPhase {
+    /// Waiting for the first line until the deadline
+    Handshake(Instant),
+    /// Receives every theme event
+    Subscribed(Protocol),
+    /// Disconnected once the reply is written
+    Closing,
+}
+
+/**
+ * A non-blocking client connection with the input and output pending on it.
+ */
+struct Client {
+    stream: UnixStream,
+    pid: Option<libc::pid_t>,
+    phase: ClientPhase,
+    /// Sequence of the last event sent to a subscriber
+    sequence: u64,
+    /// When the next heartbeat is due, for subscribers that asked for them
+    next_heartbeat: Option<Instant>,
+    inbound: Vec<u8>,
+    outbound: Vec<u8>,
+    /// Latest event held back from a stalled subscriber
+    pending_event: Option<String>,
+    /// Since when the socket did not take all of the output
+    stalled_since: Option<Instant>,
+    /// The client shut down its side of the connection, only writes are left
+    read_closed: bool,
+    /// A legacy subscriber that has not written yet, a JSON hello arriving
+    /// after the handshake timed out still switches it over
+    late_hello: bool,
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> Client {
+        Client {
+            pid: peer_pid(&stream),
+            stream,
+            phase: ClientPhase::Handshake(Instant::now() + HANDSHAKE_TIMEOUT),
+            sequence: 0,
+            next_heartbeat: None,
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            pending_event: None,
+            stalled_since: None,
+            read_closed: false,
+            late_hello: false,
+        }
+    }
+
+    /**
+     * Identifies the client in the log
+     */
+    fn name(&self) -> String {
+        self.pid
+            .map_or("client".to_string(), |pid| format!("client pid {pid}"))
+    }
+
+    /**
+     * When the event loop has to look at the client again without activity
+     * on its socket.
+     */
+    fn deadline(&self, policy: SlowClientPolicy) -> Option<Instant> {
+        let stalled = match (&self.phase, policy) {
+            (ClientPhase::Handshake(deadline), _) => return Some(*deadline),
+            (_, SlowClientPolicy::Drop) => self
+                .stalled_since
+                .map(|stalled_since| stalled_since + SLOW_CLIENT_TIMEOUT),
+            (_, SlowClientPolicy::Coalesce) => None,
+        };
+        stalled.into_iter().chain(self.next_heartbeat).min()
+    }
+
+    fn protocol(&self) -> Option<Protocol> {
+        match self.phase {
+            ClientPhase::Subscribed(protocol) => Some(protocol),
+            _ => None,
+        }
+    }
+
+    fn poll_fd(&self) -> libc::pollfd {
+        let mut events = 0;
+        if !self.read_closed {
+            events |= libc::POLLIN;
+        }
+        if !self.outbound.is_empty() {
+            events |= libc::POLLOUT;
+        }
+        libc::pollfd {
+            fd: self.stream.as_raw_fd(),
+            events,
+            revents: 0,
+        }
+    }
+
+    fn queue(&mut self, line: &str) {
+        self.outbound.extend_from_slice(line.as_bytes());
+        self.outbound.push(b'\n');
+    }
+
+    /**
+     * Queues the events a subscriber has not seen yet. A subscriber that
+     * fell behind further than the history reaches only gets the latest, as
+     * does a stalled subscriber when coalescing.
+     * @param history The latest events, oldest first
+     * @param no_preference What text subscribers receive for no preference
+     * @param policy What to do when the subscriber stalled
+     */
+    fn deliver(
+        &mut self,
+        history: &VecDeque<ThemeEvent>,
+        no_preference: &Theme,
+        policy: SlowClientPolicy,
+    ) {
+        let (Some(protocol), Some(oldest), Some(latest)) =
+            (self.protocol(), history.front(), history.back())
+        else {
+            return;
+        };
+        let seen = self.sequence;
+        if latest.sequence <= seen {
+            return;
+        }
+        self.sequence = latest.sequence;
+
+        if self.stalled_since.is_some() && policy == SlowClientPolicy::Coalesce {
+            if self.pending_event.is_none() {
+                println!(
+                    "WARNING: {} stopped reading, coalescing its theme events",
+                    self.name()
+                );
+            }
+            self.pending_event = Some(protocol.encode_event(latest, no_preference));
+        } else if oldest.sequence > seen + 1 {
+            self.queue(&protocol.encode_event(latest, no_preference));
+        } else {
+            for event in history.iter().filter(|event| event.sequence > seen) {
+                self.queue(&protocol.encode_event(event, no_preference));
+            }
+        }
+    }
+
+    /**
+     * Reads everything the client sent so far. Subscribers have nothing left
+     * to say apart from a late hello, their input is otherwise only read to
+     * notice when they hang up.
+     */
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 1024];
+        loop {
+            match (&self.stream).read(&mut buffer) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return Ok(());
+                }
+                Ok(read) => {
+                    if self.protocol().is_none() || self.late_hello {
+                        self.inbound.extend_from_slice(&buffer[..read]);
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /**
+     * Takes the first line sent by the client, which is complete once it
+     * ends with a newline or the client stopped writing.
+     */
+    fn take_line(&mut self) -> Option<String> {
+        let end = match self.inbound.iter().position(|byte| *byte == b'\n') {
+            Some(end) => end,
+            None if self.read_closed && !self.inbound.is_empty() => self.inbound.len(),
+            None => return None,
+        };
+        let line = String::from_utf8_lossy(&self.inbound[..end])
+            .trim()
+            .to_string();
+        self.inbound.clear();
+        Some(line)
+    }
+
+    /**
+     * Writes as much of the pending output as the socket takes without
+     * blocking, followed by the event held back while it stalled.
+     */
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            while !self.outbound.is_empty() {
+                match (&self.stream).write(&self.outbound) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(written) => {
+                        self.outbound.drain(..written);
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        self.stalled_since.get_or_insert_with(Instant::now);
+                        return Ok(());
+                    }
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                    Err(error) => return Err(error),
+                }
+            }
+            match self.pending_event.take() {
+                Some(event) => self.queue(&event),
+                None => break,
+            }
+        }
+        self.stalled_since = None;
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.phase, ClientPhase::Closing) && self.outbound.is_empty()
+    }
+}
+
+/**
+ * Replies to a JSON-lines hello with the welcome message.
+ */
+fn handle_hello(hello: &str) -> Result<(ServerMessage, bool)> {
+    match hello.parse()? {
+        ClientMessage::Hello { version, heartbeat } => {
+            Ok((ServerMessage::welcome(version, heartbeat)?, heartbeat))
+        }
+    }
+}
+
+/**
+ * Runs a command, returning the reply line or None when the client
+ * subscribed.
+ */
+fn handle_command(state: &Arc<DaemonState>, command: Command) -> Option<String> {
+    let reply = match command {
+        Command::Subscribe(_) => return None,
+        Command::Get => Ok(state.lock().event.to_string()),
+        Command::Set(Theme::Named(name), _) if !state.lock().variants.contains(&name) => {
+            Err(Error::Parse(format!(
+                "unknown theme variant {name:?}, no listener has a theme file for it"
+            )))
+        }
+        Command::Set(theme, duration) => state
+            .set_override(theme, duration)
+            .map(|event| event.to_string()),
+        Command::Toggle(duration) => {
+            let opposite = {
+                let theme = state.lock();
+                match theme.event.state.theme.resolve(&theme.no_preference) {
+                    Theme::Dark => Ok(Theme::Light),
+                    Theme::Light => Ok(Theme::Dark),
+                    named => Err(Error::Parse(format!(
+                        "theme {named} has no opposite, set light or dark instead"
+                    ))),
+                }
+            };
+            opposite
+                .and_then(|theme| state.set_override(theme, duration))
+                .map(|event| event.to_string())
+        }
+        Command::Clear => Ok(state
+            .update(EventSource::Override, |sources| sources.manual = None)
+            .to_string()),
+        Command::Status => {
+            let theme = state.lock();
+            let mut status = format!(
+                "{} pid={} uptime={} clients={}",
+                theme.event,
+                std::process::id(),
+                state.started.elapsed().as_secs(),
+                state.clients.load(Ordering::SeqCst)
+            );
+            if let Some(manual) = &theme.sources.manual {
+                status.push_str(&format!(" override={}", manual.theme));
+                if let Some(expires) = manual.expires {
+                    let expires_in = expires.saturating_sub(suspend_aware_now());
+                    status.push_str(&format!(" expires-in={}", expires_in.as_secs()));
+                }
+            }
+            Ok(status)
+        }
+        Command::Ping => Ok("pong".to_string()),
+        Command::Stop => {
+            // Shut down through the same path as a termination signal, the
+            // event loop writes the reply before it stops
+            unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+            Ok("stopping".to_string())
+        }
+    };
+    Some(reply.unwrap_or_else(|error| format!("error {error}")))
+}
+
+/**
+ * Sends the current theme event to the client, followed by every change.
+ * @param heartbeat Whether to send heartbeats in between
+ */
+fn subscribe(state: &DaemonState, client: &mut Client, protocol: Protocol, heartbeat: bool) {
+    let theme = state.lock();
+    client.queue(&protocol.encode_event(&theme.event, &theme.no_preference));
+    client.sequence = theme.event.sequence;
+    client.next_heartbeat = heartbeat.then(|| Instant::now() + HEARTBEAT_INTERVAL);
+    client.phase = ClientPhase::Subscribed(protocol);
+}
+
+/**
+ * Acts on the first line of a client.
+ * @param line The line, None for legacy clients that never write
+ */
+fn handle_handshake(state: &Arc<DaemonState>, client: &mut Client, line: Option<String>) {
+    let reply = match line {
+        // Legacy clients never write and are subscribed straight away
+        None => {
+            client.late_hello = true;
+            return subscribe(state, client, Protocol::Text, false);
+        }
+        Some(hello) if hello.starts_with('{') => match handle_hello(&hello) {
+            Ok((welcome, heartbeat)) => {
+                client.queue(&welcome.to_line());
+                return subscribe(state, client, Protocol::Json, heartbeat);
+            }
+            Err(error) => ServerMessage::Error {
+                message: error.to_string(),
+            }
+            .to_line(),
+        },
+        Some(command) => match command.parse() {
+            Ok(command) => {
+                let heartbeat = matches!(command, Command::Subscribe(true));
+                match handle_command(state, command) {
+                    Some(reply) => reply,
+                    None => return subscribe(state, client, Protocol::Text, heartbeat),
+                }
+            }
+            Err(error) => format!("error {error}"),
+        },
+    };
+    client.queue(&reply);
+    client.phase = ClientPhase::Closing;
+}
+
+/**
+ * Handles what poll reported for a client.
+ * @return Whether the client stays connected
+ */
+fn process_client(
+    state: &Arc<DaemonState>,
+    client: &mut Client,
+    revents: libc::c_short,
+    now: Instant,
+    policy: SlowClientPolicy,
+) -> bool {
+    if revents & (libc::POLLERR | libc::POLLNVAL) != 0 {
+        return false;
+    }
+    // A client may write a command and hang up without waiting for the
+    // reply, its input is still readable and the command runs before closing
+    let hung_up = revents & libc::POLLHUP != 0;
+    if revents & (libc::POLLIN | libc::POLLHUP) != 0 && client.receive().is_err() {
+        return false;
+    }
+
+    if client.late_hello {
+        // Only a hello sent before anything else switches to JSON
+        if let Some(line) = client.take_line() {
+            client.late_hello = false;
+            if line.starts_with('{') {
+                handle_handshake(state, client, Some(line));
+            }
+        } else if client.inbound.len() > MAX_LINE_LENGTH {
+            client.late_hello = false;
+            client.inbound.clear();
+        }
+    }
+    if let ClientPhase::Handshake(deadline) = client.phase {
+        if let Some(line) = client.take_line() {
+            handle_handshake(state, client, Some(line));
+        } else if client.inbound.len() > MAX_LINE_LENGTH {
+            client.queue("error line too long");
+            client.phase = ClientPhase::Closing;
+        } else if client.read_closed || now >= deadline {
+            handle_handshake(state, client, None);
+        }
+    }
+    if hung_up {
+        return false;
+    }
+
+    if let (Some(protocol), Some(next_heartbeat)) = (client.protocol(), client.next_heartbeat) {
+        if now >= next_heartbeat {
+            // A stalled client already has output waiting
+            if client.outbound.is_empty() {
+                client.queue(&protocol.encode_heartbeat());
+            }
+            client.next_heartbeat = Some(now + HEARTBEAT_INTERVAL);
+        }
+    }
+
+    if client.flush().is_err() {
+        return false;
+    }
+    if policy == SlowClientPolicy::Drop {
+        let stalled_for = client
+            .stalled_since
+            .map(|stalled_since| now.saturating_duration_since(stalled_since));
+        if stalled_for.is_some_and(|stalled_for| stalled_for >= SLOW_CLIENT_TIMEOUT)
+            || client.outbound.len() > MAX_OUTBOUND
+        {
+            println!("WARNING: Dropping {}, it stopped reading", client.name());
+            return false;
+        }
+    }
+    !client.is_finished()
+}
+
+/**
+ * Accepts every pending connection from authorized peers.
+ */
+fn accept_clients(listener: &UnixListener, clients: &mut Vec<Client>) {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) if !is_authorized(&stream) => {}
+            Ok((stream, _)) => match stream.set_nonblocking(true) {
+                Ok(()) => clients.push(Client::new(stream)),
+                Err(error) => println!("WARNING: Error configuring connection: {error}"),
+            },
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => {
+                println!("WARNING: Error accepting connection: {error}");
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Waits for activity on the given descriptors.
+ * @param timeout How long to wait at most, forever when None
+ */
+fn poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> io::Result<()> {
+    // Round up so a pending deadline is never polled for with a 0ms timeout
+    let timeout = timeout.map_or(-1, |timeout| {
+        timeout
+            .as_micros()
+            .div_ceil(1000)
+            .min(libc::c_int::MAX as u128) as libc::c_int
+    });
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } == -1 {
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Accepts clients, answers their commands and fans theme events out to the
+ * subscribers from a single thread until the daemon stops or waiting for
+ * the clients fails.
+ */
+fn run_event_loop(
+    state: Arc<DaemonState>,
+    listener: UnixListener,
+    policy: SlowClientPolicy,
+) -> io::Result<()> {
+    let mut clients: Vec<Client> = Vec::new();
+    let mut result = Ok(());
+
+    while !state.stopping.load(Ordering::SeqCst) {
+        let mut fds = vec![
+            libc::pollfd {
+                fd: state.wake_receiver.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        fds.extend(clients.iter().map(Client::poll_fd));
+
+        let deadline = clients
+            .iter()
+            .filter_map(|client| client.deadline(policy))
+            .min();
+        let expires_in = state
+            .expire_override()
+            .map(|expires_in| expires_in.min(OVERRIDE_CHECK_INTERVAL));
+        let timeout = deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .into_iter()
+            .chain(expires_in)
+            .min();
+        if let Err(error) = poll(&mut fds, timeout) {
+            result = Err(error);
+            break;
+        }
+
+        if fds[0].revents != 0 {
+            // Drain the wake-ups, one fan-out covers all of them
+            let mut buffer = [0; 64];
+            while (&state.wake_receiver)
+                .read(&mut buffer)
+                .is_ok_and(|read| read > 0)
+            {}
+
+            let (history, no_preference) = {
+                let theme = state.lock();
+                (theme.history.clone(), theme.no_preference.clone())
+            };
+            for client in &mut clients {
+                client.deliver(&history, &no_preference, policy);
+            }
+        }
+
+        let now = Instant::now();
+        let mut revents = fds[2..].iter().map(|fd| fd.revents);
+        clients.retain_mut(|client| {
+            let revents = revents.next().unwrap_or_default();
+            process_client(&state, client, revents, now, policy)
+        });
+
+        if fds[1].revents != 0 {
+            accept_clients(&listener, &mut clients);
+        }
+
+        let subscribers = clients
+            .iter()
+            .filter(|client| client.protocol().is_some())
+            .count();
+        state.clients.store(subscribers, Ordering::SeqCst);
+    }
+
+    for client in &mut clients {
+        // Text clients only see the connection close
+        if client.protocol() == Some(Protocol::Json) {
+            client.queue(&ServerMessage::Shutdown.to_line());
+        }
+        let _ = client.flush();
+    }
+    result
+}
+
+/**
+ * The daemon socket and the event loop serving its clients.
+ */
+pub struct Server {
+    state: Arc<DaemonState>,
+    socket_path: PathBuf,
+    /// Sockets passed by systemd outlive the daemon and are left in place
+    remove_socket: bool,
+    event_loop: Mutex<Option<JoinHandle<io::Result<()>>>>,
+}
+
+impl Server {
+    /**
+     * Serves clients on the bound socket from an event loop thread.
+     * @param state State shared with the publisher
+     * @param listener The bound daemon socket
+     * @param socket_path Where the socket is bound
+     * @param remove_socket Whether to unlink the socket on shutdown
+     * @param policy What to do with subscribers that stop reading
+     */
+    pub fn start(
+        state: Arc<DaemonState>,
+        listener: UnixListener,
+        socket_path: PathBuf,
+        remove_socket: bool,
+        policy: SlowClientPolicy,
+    ) -> Result<Server> {
+        listener.set_nonblocking(true).map_err(|error| {
+            Error::Ipc(
+                "Error while starting the listener server".to_string(),
+                error,
+            )
+        })?;
+
+        let loop_state = Arc::clone(&state);
+        let event_loop = thread::spawn(move || {
+            let result = run_event_loop(loop_state, listener, policy);
+            if result.is_err() {
+                // Stop the whole daemon the way the stop command does
+                unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+            }
+            result
+        });
+        Ok(Server {
+            state,
+            socket_path,
+            remove_socket,
+            event_loop: Mutex::new(Some(event_loop)),
+        })
+    }
+
+    /**
+     * Disconnects the clients, waits for the event loop and unlinks the
+     * socket it bound. Later calls return once the first shutdown finished.
+     * Fails when the event loop stopped serving clients on its own.
+     */
+    pub fn shutdown(&self) -> Result<()> {
+        // Held until the end so concurrent calls wait for this shutdown
+        let mut running = self
+            .event_loop
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(event_loop) = running.take() else {
+            return Ok(());
+        };
+
+        self.state.stopping.store(true, Ordering::SeqCst);
+        self.state.wake();
+        let result = match event_loop.join() {
+            Ok(result) => {
+                result.map_err(|error| Error::Ipc("Error waiting for clients".to_string(), error))
+            }
+            Err(_) => Err(Error::Daemon("The event loop panicked".to_string())),
+        };
+
+        if self.remove_socket {
+            if let Err(error) = remove_file(&self.socket_path) {
+                println!(
+                    "WARNING: Error removing socket {}: {error}",
+                    self.socket_path.display()
+                );
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+
+    fn daemon_state(theme: Theme) -> Arc<DaemonState> {
+        let event = ThemeEvent::new(ThemeState::new(theme));
+        Arc::new(DaemonState::new(event, Theme::Light, Variants::default()).unwrap())
+    }
+
+    /**
+     * A client of the event loop and the other end of its connection.
+     */
+    fn connect() -> (Client, UnixStream) {
+        let (daemon_end, client_end) = UnixStream::pair().unwrap();
+        daemon_end.set_nonblocking(true).unwrap();
+        client_end
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (Client::new(daemon_end), client_end)
+    }
+
+    fn read_all(stream: &UnixStream) -> String {
+        let mut reply = String::new();
+        (&*stream).read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn one_shot_commands() {
+        let state = daemon_state(Theme::Dark);
+        assert_eq!(
+            handle_command(&state, Command::Ping),
+            Some("pong".to_string())
+        );
+        assert!(handle_command(&state, Command::Get)
+            .unwrap()
+            .starts_with("dark sequence=1 source=startup"));
+        assert!(handle_command(&state, Command::Status)
+            .unwrap()
+            .contains(&format!(" pid={} ", std::process::id())));
+        assert_eq!(handle_command(&state, Command::Subscribe(false)), None);
+    }
+
+    #[test]
+    fn command_reply_closes_connection() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, client_end) = connect();
+        (&client_end).write_all(b"ping\n").unwrap();
+
+        let now = Instant::now();
+        assert!(!process_client(
+            &state,
+            &mut client,
+            libc::POLLIN,
+            now,
+            SlowClientPolicy::default()
+        ));
+        drop(client);
+        assert_eq!(read_all(&client_end), "pong\n");
+    }
+
+    #[test]
+    fn invalid_command_reply() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, client_end) = connect();
+        (&client_end).write_all(b"restart\n").unwrap();
+
+        process_client(
+            &state,
+            &mut client,
+            libc::POLLIN,
+            Instant::now(),
+            SlowClientPolicy::default(),
+        );
+        drop(client);
+        assert!(read_all(&client_end).starts_with("error Parse error: invalid command"));
+    }
+
+    #[test]
+    fn override_and_clear() {
+        let state = daemon_state(Theme::Light);
+        let reply = handle_command(&state, Command::Set(Theme::Dark, None)).unwrap();
+        assert!(reply.starts_with("dark sequence=2 source=override"));
+        assert!(handle_command(&state, Command::Status)
+            .unwrap()
+            .ends_with(" override=dark"));
+
+        // The publisher keeps updating the state underneath the override
+        state.update(EventSource::Portal, |sources| {
+            sources.system.theme = Theme::NoPreference;
+        });
+        assert_eq!(state.lock().event.state.theme, Theme::Dark);
+
+        let reply = handle_command(&state, Command::Clear).unwrap();
+        assert!(reply.starts_with("no-preference sequence=3 source=override"));
+    }
+
+    #[test]
+    fn override_expires() {
+        let state = daemon_state(Theme::Light);
+        let duration = Duration::from_millis(50);
+        handle_command(&state, Command::Set(Theme::Dark, Some(duration))).unwrap();
+        assert!(state
+            .expire_override()
+            .is_some_and(|expires_in| expires_in <= duration));
+        assert!(handle_command(&state, Command::Status)
+            .unwrap()
+            .ends_with(" override=dark expires-in=0"));
+
+        thread::sleep(duration);
+        assert_eq!(state.expire_override(), None);
+        assert_eq!(state.lock().event.state.theme, Theme::Light);
+        assert!(state.lock().sources.manual.is_none());
+
+        // Overrides without a duration never expire
+        handle_command(&state, Command::Set(Theme::Dark, None)).unwrap();
+        assert_eq!(state.expire_override(), None);
+        assert_eq!(state.lock().event.state.theme, Theme::Dark);
+    }
+
+    #[test]
+    fn toggle_resolves_no_preference() {
+        let event = ThemeEvent::new(ThemeState::new(Theme::NoPreference));
+        let state = Arc::new(DaemonState::new(event, Theme::Dark, Variants::default()).unwrap());
+        let reply = handle_command(&state, Command::Toggle(None)).unwrap();
+        assert!(reply.starts_with("light "));
+        let reply = handle_command(&state, Command::Toggle(None)).unwrap();
+        assert!(reply.starts_with("dark "));
+    }
+
+    #[test]
+    fn command_from_client_that_hung_up() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, client_end) = connect();
+        // Fire and forget, the client is gone before the event loop reads
+        (&client_end).write_all(b"set dark\n").unwrap();
+        drop(client_end);
+
+        let revents = libc::POLLIN | libc::POLLHUP;
+        assert!(!process_client(
+            &state,
+            &mut client,
+            revents,
+            Instant::now(),
+            SlowClientPolicy::default()
+        ));
+        assert_eq!(state.lock().event.state.theme, Theme::Dark);
+    }
+
+    #[test]
+    fn hang_up_without_command() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, client_end) = connect();
+        drop(client_end);
+
+        assert!(!process_client(
+            &state,
+            &mut client,
+            libc::POLLHUP,
+            Instant::now(),
+            SlowClientPolicy::default()
+        ));
+        assert_eq!(state.lock().event.sequence, 1);
+    }
+
+    #[test]
+    fn legacy_subscriber_after_handshake_timeout() {
+        let state = daemon_state(Theme::Dark);
+        let (mut client, client_end) = connect();
+
+        let later = Instant::now() + HANDSHAKE_TIMEOUT;
+        assert!(process_client(
+            &state,
+            &mut client,
+            0,
+            later,
+            SlowClientPolicy::default()
+        ));
+        assert_eq!(client.protocol(), Some(Protocol::Text));
+        let mut line = [0; 5];
+        (&client_end).read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"dark\n");
+    }
+
+    #[test]
+    fn late_hello_switches_to_json() {
+        let state = daemon_state(Theme::Dark);
+        let (mut client, client_end) = connect();
+        let later = Instant::now() + HANDSHAKE_TIMEOUT;
+        process_client(&state, &mut client, 0, later, SlowClientPolicy::default());
+        let mut line = [0; 5];
+        (&client_end).read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"dark\n");
+
+        (&client_end)
+            .write_all(b"{\"type\":\"hello\",\"version\":1}\n")
+            .unwrap();
+        assert!(process_client(
+            &state,
+            &mut client,
+            libc::POLLIN,
+            Instant::now(),
+            SlowClientPolicy::default()
+        ));
+        assert_eq!(client.protocol(), Some(Protocol::Json));
+        let mut reader = BufReader::new(&client_end);
+        let mut welcome = String::new();
+        reader.read_line(&mut welcome).unwrap();
+        assert!(welcome.starts_with(r#"{"type":"welcome""#));
+        let mut event = String::new();
+        reader.read_line(&mut event).unwrap();
+        assert!(event.contains(r#""theme":"dark""#));
+    }
+
+    #[test]
+    fn late_input_keeps_text_subscriber() {
+        let state = daemon_state(Theme::Dark);
+        let (mut client, client_end) = connect();
+        handle_handshake(&state, &mut client, None);
+        (&client_end).write_all(b"get\n").unwrap();
+        assert!(process_client(
+            &state,
+            &mut client,
+            libc::POLLIN,
+            Instant::now(),
+            SlowClientPolicy::default()
+        ));
+        assert_eq!(client.protocol(), Some(Protocol::Text));
+        assert!(!client.late_hello);
+        assert!(client.inbound.is_empty());
+    }
+
+    fn set_theme(state: &DaemonState, theme: Theme) {
+        state.update(EventSource::Portal, |sources| sources.system.theme = theme);
+    }
+
+    fn outbound(client: &mut Client) -> String {
+        String::from_utf8(std::mem::take(&mut client.outbound)).unwrap()
+    }
+
+    #[test]
+    fn deliver_every_missed_event_once() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, _client_end) = connect();
+        subscribe(&state, &mut client, Protocol::Text, false);
+        assert_eq!(outbound(&mut client), "light\n");
+
+        // Changes landing between the subscription and the fan-out
+        set_theme(&state, Theme::Dark);
+        set_theme(&state, Theme::Light);
+        let history = state.lock().history.clone();
+        client.deliver(&history, &Theme::Light, SlowClientPolicy::default());
+        assert_eq!(outbound(&mut client), "dark\nlight\n");
+        assert_eq!(client.sequence, 3);
+
+        // Spurious wake-ups send nothing again
+        client.deliver(&history, &Theme::Light, SlowClientPolicy::default());
+        assert_eq!(outbound(&mut client), "");
+    }
+
+    #[test]
+    fn deliver_latest_beyond_history() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, _client_end) = connect();
+        subscribe(&state, &mut client, Protocol::Text, false);
+        outbound(&mut client);
+
+        for index in 0..=EVENT_HISTORY {
+            let theme = if index % 2 == 0 {
+                Theme::Dark
+            } else {
+                Theme::Light
+            };
+            set_theme(&state, theme);
+        }
+        let history = state.lock().history.clone();
+        assert_eq!(history.len(), EVENT_HISTORY);
+        client.deliver(&history, &Theme::Light, SlowClientPolicy::default());
+        assert_eq!(outbound(&mut client), "dark\n");
+        assert_eq!(client.sequence, EVENT_HISTORY as u64 + 2);
+    }
+
+    #[test]
+    fn deliver_nothing_before_subscribing() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, _client_end) = connect();
+        set_theme(&state, Theme::Dark);
+
+        let history = state.lock().history.clone();
+        client.deliver(&history, &Theme::Light, SlowClientPolicy::default());
+        assert_eq!(outbound(&mut client), "");
+    }
+
+    #[test]
+    fn parse_slow_client_policy() {
+        assert_eq!(
+            "drop".parse::<SlowClientPolicy>().unwrap(),
+            SlowClientPolicy::Drop
+        );
+        assert_eq!(
+            "coalesce".parse::<SlowClientPolicy>().unwrap(),
+            SlowClientPolicy::Coalesce
+        );
+        for value in ["", "Drop", "block", "drop coalesce"] {
+            assert!(
+                matches!(value.parse::<SlowClientPolicy>(), Err(Error::Config(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    /**
+     * Subscribes the client and fills its socket until writes would block.
+     */
+    fn stall(state: &DaemonState, client: &mut Client) {
+        subscribe(state, client, Protocol::Text, false);
+        let filler = "x".repeat(1023);
+        while client.stalled_since.is_none() {
+            client.queue(&filler);
+            client.flush().unwrap();
+        }
+    }
+
+    /**
+     * Reads what the daemon sent so far without blocking.
+     */
+    fn drain(stream: &UnixStream) -> String {
+        stream.set_nonblocking(true).unwrap();
+        let mut output = Vec::new();
+        let mut buffer = [0; 4096];
+        while let Ok(read) = (&*stream).read(&mut buffer) {
+            output.extend_from_slice(&buffer[..read]);
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn coalesce_events_of_stalled_subscriber() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, client_end) = connect();
+        stall(&state, &mut client);
+
+        set_theme(&state, Theme::Dark);
+        set_theme(&state, Theme::Light);
+        set_theme(&state, Theme::Dark);
+        let history = state.lock().history.clone();
+        client.deliver(&history, &Theme::Light, SlowClientPolicy::Coalesce);
+        assert_eq!(client.pending_event.as_deref(), Some("dark"));
+
+        // Once the subscriber reads again only the latest event follows
+        let mut received = drain(&client_end);
+        while client.stalled_since.is_some() || !client.outbound.is_empty() {
+            client.flush().unwrap();
+            received.push_str(&drain(&client_end));
+        }
+        let events: Vec<&str> = received
+            .lines()
+            .filter(|line| !line.starts_with('x'))
+            .collect();
+        assert_eq!(events, ["light", "dark"]);
+    }
+
+    #[test]
+    fn drop_stalled_subscriber() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, _client_end) = connect();
+        stall(&state, &mut client);
+        let stalled_since = client.stalled_since.unwrap();
+
+        let policy = SlowClientPolicy::Drop;
+        assert_eq!(
+            client.deadline(policy),
+            Some(stalled_since + SLOW_CLIENT_TIMEOUT)
+        );
+        assert!(process_client(
+            &state,
+            &mut client,
+            0,
+            stalled_since,
+            policy
+        ));
+        assert!(!process_client(
+            &state,
+            &mut client,
+            0,
+            stalled_since + SLOW_CLIENT_TIMEOUT,
+            policy
+        ));
+    }
+
+    #[test]
+    fn heartbeats_for_subscribers_asking_for_them() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, client_end) = connect();
+        (&client_end).write_all(b"subscribe heartbeat\n").unwrap();
+
+        let now = Instant::now();
+        let policy = SlowClientPolicy::default();
+        assert!(process_client(
+            &state,
+            &mut client,
+            libc::POLLIN,
+            now,
+            policy
+        ));
+        let next_heartbeat = client.next_heartbeat.unwrap();
+        assert!(next_heartbeat >= now + HEARTBEAT_INTERVAL);
+        assert_eq!(client.deadline(policy), Some(next_heartbeat));
+        assert!(process_client(
+            &state,
+            &mut client,
+            0,
+            next_heartbeat,
+            policy
+        ));
+        assert_eq!(drain(&client_end), "light\nheartbeat\n");
+        assert_eq!(
+            client.next_heartbeat,
+            Some(next_heartbeat + HEARTBEAT_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn no_heartbeats_by_default() {
+        let state = daemon_state(Theme::Light);
+        let (mut client, _client_end) = connect();
+        subscribe(&state, &mut client, Protocol::Json, false);
+        assert_eq!(client.deadline(SlowClientPolicy::default()), None);
+    }
+
+    /**
+     * Hands every theme it is asked to apply to the test.
+     */
+    #[derive(Clone)]
+    struct Recorder(Sender<ThemeState>);
+
+    impl ThemeListener<()> for Recorder {
+        fn handle(self, next_theme_state: ThemeState) -> Result<()> {
+            let _ = self.0.send(next_theme_state);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hosted_listener_applies_changes() {
+        let state = daemon_state(Theme::Light);
+        let (sender, applied) = mpsc::channel();
+        state
+            .host_listener(Arc::new(Mutex::new(Recorder(sender))))
+            .unwrap();
+        let next = || applied.recv_timeout(Duration::from_secs(5)).unwrap().theme;
+        assert_eq!(next(), Theme::Light);
+
+        set_theme(&state, Theme::Dark);
+        assert_eq!(next(), Theme::Dark);
+        state.reapply();
+        assert_eq!(next(), Theme::Dark);
+
+        let (other_sender, _) = mpsc::channel();
+        let hosted_again = state.host_listener(Arc::new(Mutex::new(Recorder(other_sender))));
+        assert!(matches!(hosted_again, Err(Error::Daemon(_))));
+    }
+
+    #[test]
+    fn text_subscribers_never_see_no_preference() {
+        let event = ThemeEvent::new(ThemeState::new(Theme::NoPreference));
+        let state = Arc::new(DaemonState::new(event, Theme::Dark, Variants::default()).unwrap());
+        let (mut text_client, _text_end) = connect();
+        let (mut json_client, _json_end) = connect();
+        subscribe(&state, &mut text_client, Protocol::Text, false);
+        subscribe(&state, &mut json_client, Protocol::Json, false);
+        assert_eq!(outbound(&mut text_client), "dark\n");
+        assert!(outbound(&mut json_client).contains(r#""theme":"no-preference""#));
+
+        set_theme(&state, Theme::Light);
+        set_theme(&state, Theme::NoPreference);
+        let history = state.lock().history.clone();
+        text_client.deliver(&history, &Theme::Dark, SlowClientPolicy::default());
+        assert_eq!(outbound(&mut text_client), "light\ndark\n");
+    }
+
+    #[test]
+    fn shutdown_disconnects_subscribers() {
+        let _env = crate::lock_env();
+        let socket_path = std::env::temp_dir().join(format!(
+            "theme-listener-{}-shutdown.sock",
+            std::process::id()
+        ));
+        let _ = remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let state = daemon_state(Theme::Light);
+        let server = Server::start(
+            Arc::clone(&state),
+            listener,
+            socket_path.clone(),
+            true,
+            SlowClientPolicy::default(),
+        )
+        .unwrap();
+
+        let mut subscriber = UnixStream::connect(&socket_path).unwrap();
+        subscriber
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut line = [0; 6];
+        subscriber.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"light\n");
+
+        server.shutdown().unwrap();
+        assert!(!socket_path.exists());
+        assert_eq!(subscriber.read(&mut line).unwrap(), 0);
+        // Later calls find the event loop already stopped
+        server.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::{Error, Result};
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+
+/**
+ * Forks, returning the PID of the child in the parent and 0 in the child.
+ */
+fn fork() -> Result<libc::pid_t> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::Ipc(
+            "Error forking".to_string(),
+            io::Error::last_os_error(),
+        )),
+        pid => Ok(pid),
+    }
+}
+
+/**
+ * Points stdin at /dev/null and stdout and stderr at the log file, or at
+ * /dev/null when no log file is given.
+ */
+fn redirect_stdio(log_path: Option<&Path>) -> Result<()> {
+    let redirect_error =
+        |error: io::Error| Error::Ipc("Error redirecting stdio".to_string(), error);
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(redirect_error)?;
+    let log = match log_path {
+        Some(log_path) => OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(log_path)
+            .map_err(|error| {
+                Error::Ipc(format!("Error opening log {}", log_path.display()), error)
+            })?,
+        None => null.try_clone().map_err(redirect_error)?,
+    };
+
+    for (file, target) in [
+        (&null, libc::STDIN_FILENO),
+        (&log, libc::STDOUT_FILENO),
+        (&log, libc::STDERR_FILENO),
+    ] {
+        if unsafe { libc::dup2(file.as_raw_fd(), target) } == -1 {
+            return Err(redirect_error(io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Detaches the process from the terminal it was started from: double fork
+ * with setsid in between, chdir to /, stdio redirected and the PID written
+ * to the pidfile. The original process only returns once the pidfile is
+ * written, so commands run right after it find the daemon.
+ * @param log_path File receiving stdout and stderr, /dev/null when None
+ * @param pid_path File the daemon PID is written to, if any
+ * @return true in the daemon, false in the original process which should exit
+ */
+pub fn daemonize(log_path: Option<&Path>, pid_path: Option<&Path>) -> Result<bool> {
+    let (mut ready_receiver, mut ready_sender) = UnixStream::pair()
+        .map_err(|error| Error::Ipc("Error creating daemon pipe".to_string(), error))?;
+
+    let child = fork()?;
+    if child != 0 {
+        drop(ready_sender);
+        // Reap the session leader, which exits as soon as it forked the daemon
+        unsafe { libc::waitpid(child, ptr::null_mut(), 0) };
+
+        // The daemon closes its end without a byte when it fails to start
+        let mut ready = [0; 1];
+        return match ready_receiver.read(&mut ready) {
+            Ok(1) => Ok(false),
+            _ => Err(Error::Daemon("Daemon failed to start".to_string())),
+        };
+    }
+    drop(ready_receiver);
+
+    // Leave the session of the terminal so closing it sends no SIGHUP
+    if unsafe { libc::setsid() } == -1 {
+        return Err(Error::Ipc(
+            "Error creating session".to_string(),
+            io::Error::last_os_error(),
+        ));
+    }
+
+    // The session leader exits so the daemon can never reacquire a terminal
+    if fork()? != 0 {
+        unsafe { libc::_exit(0) };
+    }
+
+    // Do not keep the directory the daemon was started from busy
+    std::env::set_current_dir("/")
+        .map_err(|error| Error::Ipc("Error changing directory to /".to_string(), error))?;
+
+    if let Some(pid_path) = pid_path {
+        let pid_error =
+            |error: io::Error| Error::Ipc(format!("Error writing {}", pid_path.display()), error);
+        let mut pid_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(pid_path)
+            .map_err(pid_error)?;
+        writeln!(pid_file, "{}", std::process::id()).map_err(pid_error)?;
+    }
+
+    redirect_stdio(log_path)?;
+    let _ = ready_sender.write_all(&[1]);
+    Ok(true)
+}
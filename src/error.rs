@@ -0,0 +1,61 @@
+use std::fmt;
+use std::io;
+
+/**
+ * Errors raised across the daemon, its publishers and the listeners.
+ */
+#[derive(Debug)]
+pub enum Error {
+    /// A value read from the socket or a publisher could not be parsed
+    Parse(String),
+    /// Communication over a UNIX socket failed
+    Ipc(String, io::Error),
+    /// The session bus or the desktop portal returned an error
+    #[cfg(target_os = "linux")]
+    DBus(dbus::Error),
+    /// AppKit did not provide the appearance information
+    #[cfg(target_os = "macos")]
+    AppKit(String),
+    /// The environment or a theme file is misconfigured
+    Config(String),
+    /// A listener failed to apply a theme
+    Listener(String),
+    /// The daemon replied with an error to a command
+    Daemon(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(message) => write!(f, "Parse error: {message}"),
+            Error::Ipc(message, error) => write!(f, "{message}: {error}"),
+            #[cfg(target_os = "linux")]
+            Error::DBus(error) => write!(f, "D-Bus error: {error}"),
+            #[cfg(target_os = "macos")]
+            Error::AppKit(message) => write!(f, "AppKit error: {message}"),
+            Error::Config(message) => write!(f, "Configuration error: {message}"),
+            Error::Listener(message) => write!(f, "Listener error: {message}"),
+            Error::Daemon(message) => write!(f, "Daemon error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Ipc(_, error) => Some(error),
+            #[cfg(target_os = "linux")]
+            Error::DBus(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<dbus::Error> for Error {
+    fn from(error: dbus::Error) -> Self {
+        Error::DBus(error)
+    }
+}
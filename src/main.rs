@@ -1,201 +1,622 @@
 // Theme modules
+mod client;
+mod config;
+mod daemon;
+mod daemonize;
+mod error;
+mod protocol;
+mod signal;
+mod socket;
+mod systemd;
 mod theme;
 mod theme_listener;
 mod theme_publisher;
 
 // Theme import
-use crate::theme::Theme;
-use crate::theme_listener::ThemeListener;
+use crate::client::{daemon_status, handle_stream, is_daemon_running, send_command, stop_daemon};
+use crate::config::{Config, ConfigFile};
+use crate::daemon::{listen_theme, DaemonState, Server, SlowClientPolicy};
+use crate::daemonize::daemonize;
+use crate::error::{Error, Result};
+use crate::signal::{exit_immediately, SignalPipe};
+use crate::socket::{bind, lock, socket_path};
+use crate::theme::{no_preference_policy, ThemeEvent};
 use crate::theme_publisher::ThemePublisher;
 
 // Publisher
 use theme_publisher::create_publisher;
 
 // Listeners
-use crate::theme_listener::alacritty::Alacritty;
-use crate::theme_listener::tmux::Tmux;
-
-use std::error::Error;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::os::unix::net::{UnixListener, UnixStream};
+use crate::theme_listener::{Listener, Listeners, Variants, LISTENER_NAMES};
 
 // Threads and communication
-use std::sync::{Arc, Condvar, Mutex};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
 
 // To check whether socket exists
-use std::fs::{exists, remove_file};
-
-// For libc signal handling
-use libc::{fork, sigaction, SA_SIGINFO, SIGHUP, SIGINT, SIGTERM};
-use std::mem;
-use std::process::exit;
-use std::ptr;
-
-const SOCKET_PATH: &str = "/tmp/theme-listener.sock";
-
-// sigaction definition specific to os
-cfg_if::cfg_if!(
-    if #[cfg(target_os = "linux")] {
-        unsafe fn setup_signal_handler() -> sigaction {
-            sigaction {
-                sa_sigaction: handle_terminate as usize,
-                sa_flags: SA_SIGINFO,
-                sa_restorer: None,
-                sa_mask: mem::zeroed(),
-            }
+use std::fs::{exists, remove_file, File};
+
+// Signals ending the daemon
+use libc::{SIGHUP, SIGINT, SIGTERM};
+use std::process::ExitCode;
+
+// Environment variable setting the slow client policy
+const SLOW_CLIENTS_ENV: &str = "THEME_LISTENER_SLOW_CLIENTS";
+// What toggle and text subscribers treat a "no preference" theme as
+const NO_PREFERENCE_ENV: &str = "THEME_LISTENER_NO_PREFERENCE_THEME";
+
+// Signals that shut the detached process down
+const TERMINATE_SIGNALS: [libc::c_int; 3] = [SIGINT, SIGTERM, SIGHUP];
+
+// Held by the tests that read or change environment variables
+#[cfg(test)]
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/**
+ * Runs tests touching the environment one at a time, glibc does not allow
+ * changing it while another thread reads it. Reading includes
+ * std::env::temp_dir and the socket and theme file lookups.
+ */
+#[cfg(test)]
+fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+    ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/**
+ * Collects the values following a flag up to the next flag, None when the
+ * flag was not passed.
+ */
+fn flag_values(flag: &str) -> Option<Vec<String>> {
+    let mut args = std::env::args().skip_while(|arg| arg != flag);
+    args.next()?;
+    Some(args.take_while(|arg| !arg.starts_with('-')).collect())
+}
+
+/**
+ * The single path passed with a flag, e.g. -socket /path/to/socket. Relative
+ * paths are resolved up front as detached processes chdir to /.
+ */
+fn flag_value(flag: &str) -> Option<PathBuf> {
+    let value = PathBuf::from(flag_values(flag)?.into_iter().next()?);
+    Some(std::path::absolute(&value).unwrap_or(value))
+}
+
+/**
+ * Reads the config file. Commands for a running daemon only need its socket,
+ * so they fall back to the defaults when the file cannot be read.
+ * @param config_file The config file to read
+ * @param is_command Whether a daemon command or lifecycle flag was passed
+ */
+fn load_config(config_file: &ConfigFile, is_command: bool) -> Result<Config> {
+    match config_file.load() {
+        Err(error) if is_command => {
+            println!("WARNING: Ignoring the config, {error}");
+            Ok(Config::default())
         }
-    } else if #[cfg(target_os = "macos")] {
-        unsafe fn setup_signal_handler() -> sigaction {
-            sigaction {
-                sa_sigaction: handle_terminate as usize,
-                sa_flags: SA_SIGINFO,
-                sa_mask: mem::zeroed(),
+        result => result,
+    }
+}
+
+/**
+ * Whether the daemon runs the listeners itself, asked for with -host or
+ * host_listeners = true.
+ * @param config The config file settings
+ */
+fn hosts_listeners(config: &Config) -> bool {
+    std::env::args().any(|arg| arg == "-host") || config.host_listeners
+}
+
+/**
+ * Names of the listeners whose flag was passed, e.g. -alacritty -tmux, or
+ * the configured listeners without any. The configured listeners belong to
+ * either the daemon or the client, so themes are never applied twice.
+ * @param config The config file settings
+ * @param hosted Whether the listeners run inside the daemon
+ */
+fn listener_names(config: &Config, hosted: bool) -> Vec<&str> {
+    if hosted && !hosts_listeners(config) {
+        return Vec::new();
+    }
+    let names: Vec<&str> = LISTENER_NAMES
+        .into_iter()
+        .filter(|name| std::env::args().any(|arg| arg == format!("-{name}")))
+        .collect();
+    if names.is_empty() && (hosted || !config.host_listeners) {
+        return config.listeners.iter().map(String::as_str).collect();
+    }
+    names
+}
+
+/**
+ * Creates the enabled listeners. Listeners that cannot be created are
+ * skipped, it only fails when none of them could be.
+ * @param config The config file settings
+ * @param hosted Whether the listeners run inside the daemon
+ */
+fn enabled_listeners(config: &Config, hosted: bool) -> Result<Listeners> {
+    let mut listeners = Vec::new();
+    let mut last_error = None;
+    for name in listener_names(config, hosted) {
+        match Listener::new(name, config) {
+            Ok(listener) => listeners.push(listener),
+            Err(error) => {
+                println!("WARNING: Skipping the {name} listener, {error}");
+                last_error = Some(error);
             }
         }
     }
-);
+    match last_error {
+        Some(error) if listeners.is_empty() => Err(error),
+        _ => Ok(Listeners::new(listeners)),
+    }
+}
+
+/**
+ * Applies a changed config file to the running daemon. The listeners are
+ * rebuilt in place, the socket and the connected clients stay. Nothing
+ * changes unless the new config is valid and every one of its listeners
+ * can be created.
+ * @param config_file The config file that changed
+ * @param config The config the daemon runs with
+ * @param listeners The listeners hosted in the daemon
+ * @param state Takes the new theme policies and re-applies the current theme
+ */
+fn reload_config(
+    config_file: &ConfigFile,
+    config: &mut Config,
+    listeners: &Mutex<Listeners>,
+    state: &DaemonState,
+) -> Result<()> {
+    let next_config = config_file.load()?;
+    if next_config == *config {
+        return Ok(());
+    }
+    // Unlike on startup a broken listener keeps the working ones running
+    let next_listeners = listener_names(&next_config, true)
+        .into_iter()
+        .map(|name| Listener::new(name, &next_config))
+        .collect::<Result<Vec<Listener>>>()?;
+    let no_preference =
+        no_preference_policy(NO_PREFERENCE_ENV, next_config.no_preference.as_deref())?;
 
-fn write_to_stream(stream: &mut BufWriter<UnixStream>, value: String) -> io::Result<()> {
-    stream.write_all(format!("{value}\n").as_bytes())?;
-    stream.flush()
+    if next_config.socket != config.socket || next_config.slow_clients != config.slow_clients {
+        println!("WARNING: Socket and slow client changes apply after a restart");
+    }
+    let listeners_differ = next_config.listeners_differ(config);
+    *config = next_config;
+    state.set_themes(no_preference, Variants::new(config));
+    if listeners_differ {
+        *listeners.lock().unwrap_or_else(PoisonError::into_inner) = Listeners::new(next_listeners);
+        state.reapply();
+    }
+    println!("Reloaded config {}", config_file.path.display());
+    Ok(())
 }
 
-fn handle_stream<A, B>(listener: A)
-where
-    A: ThemeListener<B> + Clone,
-{
-    let Ok(theme_stream) = UnixStream::connect(SOCKET_PATH) else {
-        panic!("Error listening to server");
+/**
+ * Binds the socket unless another daemon owns it, taking over stale sockets.
+ * @param socket_path The socket to bind
+ * @return The listener and the lock to hold while the daemon runs, None when
+ * another daemon owns the socket
+ */
+fn acquire_socket(socket_path: &Path) -> Result<Option<(UnixListener, File)>> {
+    // Another -init is already starting the daemon
+    let Some(socket_lock) = lock(socket_path)? else {
+        return Ok(None);
     };
 
-    let mut reader = BufReader::new(theme_stream);
-
-    loop {
-        let mut content = String::new();
-        if let Ok(_) = reader.read_line(&mut content) {
-            let theme_value = theme::to_theme(content.trim());
-            listener.clone().handle(theme_value).unwrap();
+    let socket_exists = exists(socket_path).map_err(|error| {
+        Error::Ipc(
+            format!("Error checking socket {}", socket_path.display()),
+            error,
+        )
+    })?;
+    if socket_exists {
+        if is_daemon_running(socket_path) {
+            return Ok(None);
         }
+
+        // Left behind by a daemon that did not shut down cleanly
+        println!("WARNING: Removing stale socket {}", socket_path.display());
+        remove_file(socket_path).map_err(|error| {
+            Error::Ipc(
+                format!("Error removing stale socket {}", socket_path.display()),
+                error,
+            )
+        })?;
+    }
+
+    // Start the UNIX socket server
+    Ok(Some((bind(socket_path)?, socket_lock)))
+}
+
+/**
+ * Sends a state change to systemd, only warning when it cannot be reached.
+ */
+fn notify_service(state: &str) {
+    if let Err(error) = systemd::notify(state) {
+        println!("WARNING: {error}");
     }
 }
 
-fn listen_theme<A, B>(publisher: A, condvar_pair: Arc<(Mutex<Theme>, Condvar)>)
-where
-    A: ThemePublisher<B>,
-{
-    publisher.on_publish(Box::new(move |value: Theme| {
-        let (mutex, condvar) = &*condvar_pair;
-        let mut current_theme_value = mutex.lock().unwrap();
-        *current_theme_value = value;
-        condvar.notify_all();
-        ()
-    }));
+/**
+ * Tells systemd and the clients that the daemon stops, then removes the
+ * socket and the pidfile. Fails when the server had stopped serving clients.
+ */
+fn shutdown_daemon(server: &Server, pid_path: Option<&Path>) -> Result<()> {
+    notify_service("STOPPING=1");
+    let result = server.shutdown();
+    if let Some(pid_path) = pid_path {
+        let _ = remove_file(pid_path);
+    }
+    result
 }
 
-fn handle_connect<A, B>(
-    publisher: A,
-    condvar_pair: Arc<(Mutex<Theme>, Condvar)>,
-    socket_stream: UnixStream,
-) where
-    A: ThemePublisher<B>,
-{
-    // Handle stream here
-    let mut stream = BufWriter::new(socket_stream);
-    if let Ok(value) = publisher.fetch() {
-        // Use stream to send theme value
-        if let Err(_) = write_to_stream(&mut stream, value.to_string()) {
-            return ();
+/**
+ * Binds the socket and detaches the daemon, then publishes theme changes to
+ * its clients. Returns straight away when a daemon already owns the socket.
+ * As a systemd service the daemon stays in the foreground and may be handed
+ * its socket through socket activation.
+ * @param socket_path The socket to bind
+ * @param log_path File receiving the daemon output, /dev/null when None
+ * @param pid_path File the daemon PID is written to
+ * @param policy What to do with subscribers that stop reading
+ * @param listeners Listeners applying the themes inside the daemon
+ * @param config_file Reloaded whenever it changes
+ * @param config The settings read from config_file
+ */
+fn start_daemon(
+    socket_path: PathBuf,
+    log_path: Option<PathBuf>,
+    pid_path: PathBuf,
+    policy: SlowClientPolicy,
+    listeners: Listeners,
+    config_file: ConfigFile,
+    mut config: Config,
+) -> Result<()> {
+    let no_preference = no_preference_policy(NO_PREFERENCE_ENV, config.no_preference.as_deref())?;
+    let activated_listener = systemd::activated_listener()?;
+    let is_service = activated_listener.is_some() || systemd::is_notify_service();
+    // Read before any thread starts as the variables are removed
+    let watchdog_timeout = systemd::watchdog_timeout();
+    let remove_socket = activated_listener.is_none();
+
+    let (listener, _socket_lock) = match activated_listener {
+        // systemd owns the socket and starts a single daemon for it
+        Some(listener) => (listener, None),
+        None => match acquire_socket(&socket_path)? {
+            Some((listener, socket_lock)) => (listener, Some(socket_lock)),
+            None => return Ok(()),
+        },
+    };
+
+    // systemd tracks the service PID and collects its output itself
+    let pid_path = (!is_service).then_some(pid_path);
+    // If parent exit
+    if !is_service && !daemonize(log_path.as_deref(), pid_path.as_deref())? {
+        return Ok(());
+    }
+
+    let mut signals = SignalPipe::install(&TERMINATE_SIGNALS)?;
+
+    let publisher = create_publisher();
+    let theme_event = ThemeEvent::new(publisher.fetch()?);
+    let daemon_state = Arc::new(DaemonState::new(
+        theme_event,
+        no_preference,
+        Variants::new(&config),
+    )?);
+    // Hosted even without listeners, a reloaded config may add some
+    let listeners = Arc::new(Mutex::new(listeners));
+    daemon_state.host_listener(Arc::clone(&listeners))?;
+    let server = Arc::new(Server::start(
+        Arc::clone(&daemon_state),
+        listener,
+        socket_path,
+        remove_socket,
+        policy,
+    )?);
+    notify_service(&format!("READY=1\nSTATUS={}", daemon_state.summary()));
+
+    if let Some(watchdog_timeout) = watchdog_timeout {
+        let watchdog_state = Arc::clone(&daemon_state);
+        thread::spawn(move || loop {
+            thread::sleep(watchdog_timeout / 2);
+            // The summary takes the state lock, a deadlocked daemon stops the keepalives
+            notify_service(&format!("WATCHDOG=1\nSTATUS={}", watchdog_state.summary()));
+        });
+    }
+
+    let reload_state = Arc::clone(&daemon_state);
+    thread::spawn(move || {
+        let result = config_file.watch(|| {
+            if let Err(error) = reload_config(&config_file, &mut config, &listeners, &reload_state)
+            {
+                println!("WARNING: Keeping the current config, {error}");
+            }
+        });
+        if let Err(error) = result {
+            println!("WARNING: {error}");
         }
-    } else {
-        println!("WARNING: Error while fetching theme information");
+    });
+
+    // The publisher keeps the main thread, so shut down from another one
+    let signal_server = Arc::clone(&server);
+    let signal_pid_path = pid_path.clone();
+    thread::spawn(move || {
+        if let Ok(signal) = signals.wait() {
+            println!("Received signal {signal}, shutting down");
+        }
+        let status = match shutdown_daemon(&signal_server, signal_pid_path.as_deref()) {
+            Ok(()) => 0,
+            Err(error) => {
+                eprintln!("theme-listener: {error}");
+                1
+            }
+        };
+        // The publisher stays blocked in the OS event loop and the listener,
+        // config and override threads sleep, none of them holds on to state
+        // that still needs saving
+        exit_immediately(status);
+    });
+
+    // Only returns when the publisher fails
+    let result = listen_theme(publisher, daemon_state);
+    let shutdown = shutdown_daemon(&server, pid_path.as_deref());
+    result.and(shutdown)
+}
+
+fn run() -> Result<()> {
+    let has_flag = |flag: &str| std::env::args().any(|args| args == flag);
+    // Settings from the config file, flags and environment variables win
+    let config_file = ConfigFile::new(flag_value("-config"));
+    // A broken config must not lock the user out of the running daemon
+    let is_command = ["-set", "-toggle", "-clear", "-status", "-stop", "-restart"]
+        .into_iter()
+        .any(has_flag);
+    let config = load_config(&config_file, is_command)?;
+
+    let socket_path = socket_path(flag_value("-socket"), config.socket.clone());
+    let log_path = flag_value("-log");
+
+    // What the daemon does with subscribers that stop reading, e.g.
+    // -slow-clients drop
+    let slow_client_policy = match flag_values("-slow-clients") {
+        Some(values) => values.first().cloned(),
+        None => std::env::var(SLOW_CLIENTS_ENV)
+            .ok()
+            .or_else(|| config.slow_clients.clone()),
+    };
+    let slow_client_policy: SlowClientPolicy = match slow_client_policy {
+        Some(policy) => policy.parse()?,
+        None => SlowClientPolicy::default(),
+    };
+
+    // Manual override commands, e.g. -set dark 2h, -toggle or -clear
+    for (flag, command) in [("-set", "set"), ("-toggle", "toggle"), ("-clear", "clear")] {
+        if let Some(values) = flag_values(flag) {
+            let command = [vec![command.to_string()], values].concat().join(" ");
+            println!("{}", send_command(&socket_path, &command)?);
+            return Ok(());
+        }
+    }
+
+    // Daemon lifecycle
+    let daemon_pid_path = || flag_value("-pidfile").unwrap_or(socket_path.with_extension("pid"));
+    if has_flag("-status") {
+        println!("{}", daemon_status(&socket_path)?);
+        return Ok(());
+    } else if has_flag("-stop") {
+        if !stop_daemon(&socket_path, &daemon_pid_path())? {
+            return Err(Error::Daemon("Daemon is not running".to_string()));
+        }
+        return Ok(());
+    } else if has_flag("-restart") {
+        // Keep the running daemon when the listeners cannot be set up
+        let listeners = enabled_listeners(&config, true)?;
+        // Start the daemon even when none was running
+        stop_daemon(&socket_path, &daemon_pid_path())?;
+        return start_daemon(
+            socket_path.clone(),
+            log_path,
+            daemon_pid_path(),
+            slow_client_policy,
+            listeners,
+            config_file,
+            config,
+        );
+    } else if has_flag("-init") {
+        return start_daemon(
+            socket_path.clone(),
+            log_path,
+            daemon_pid_path(),
+            slow_client_policy,
+            enabled_listeners(&config, true)?,
+            config_file,
+            config,
+        );
     }
 
-    let (mutex, condvar) = &*condvar_pair;
-    let mut theme_value = mutex.lock().unwrap();
-    loop {
-        theme_value = condvar.wait(theme_value).unwrap();
-        // On error stop block listen to theme value
-        if let Err(_) = write_to_stream(&mut stream, theme_value.to_string()) {
-            break;
+    // The listeners share one connection, or run inside the daemon when
+    // passed with -init -host. Built up front so -d reports their errors
+    let listeners = enabled_listeners(&config, false)?;
+    if has_flag("-d") {
+        let pid_path = flag_value("-pidfile");
+        // If parent process then terminate
+        if !daemonize(log_path.as_deref(), pid_path.as_deref())? {
+            return Ok(());
         }
+        if let Some(pid_path) = pid_path {
+            let mut signals = SignalPipe::install(&TERMINATE_SIGNALS)?;
+            thread::spawn(move || {
+                let _ = signals.wait();
+                let _ = remove_file(&pid_path);
+                exit_immediately(0);
+            });
+        }
+    }
+
+    if !listeners.is_empty() {
+        handle_stream(&socket_path, listeners)?;
     }
+
+    Ok(())
 }
 
-extern "C" fn handle_terminate() {
-    // Delete the socket file
-    match remove_file(SOCKET_PATH) {
-        Ok(_) => {}
-        Err(_) => println!("Error removing socket file"),
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("theme-listener: {error}");
+            ExitCode::FAILURE
+        }
     }
-    exit(0);
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    unsafe {
-        if std::env::args().any(|args| args == "-init") {
-            if exists(SOCKET_PATH)? {
-                return Ok(());
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Start the UNIX socket server
-            let Ok(listener) = UnixListener::bind(SOCKET_PATH) else {
-                panic!("Error while starting the listener server");
-            };
+    use crate::theme::{Theme, ThemeState};
+    use std::fs::DirBuilder;
+    use std::os::unix::fs::DirBuilderExt;
+    use std::os::unix::net::UnixStream;
 
-            let process_id = fork();
-            // If parent exit
-            if process_id != 0 {
-                return Ok(());
-            }
+    /**
+     * An empty private directory for the sockets of a test.
+     */
+    fn socket_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("theme-listener-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        DirBuilder::new().mode(0o700).create(&directory).unwrap();
+        directory
+    }
 
-            // On exit delete the socket file
-            let action = setup_signal_handler();
-            sigaction(SIGINT, &action, ptr::null_mut());
-            sigaction(SIGTERM, &action, ptr::null_mut());
-            sigaction(SIGHUP, &action, ptr::null_mut());
+    #[test]
+    fn take_over_stale_socket() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("stale");
+        let socket_path = directory.join("theme-listener.sock");
+        // A daemon killed with SIGKILL leaves its socket behind
+        drop(UnixListener::bind(&socket_path).unwrap());
+        assert!(UnixStream::connect(&socket_path).is_err());
 
-            let publisher = create_publisher();
-            let theme_condvar_main_pair =
-                Arc::new((Mutex::new(publisher.fetch().unwrap()), Condvar::new()));
-            let theme_condvar_pub_pair = Arc::clone(&theme_condvar_main_pair);
-            let theme_condvar_sub_pair = Arc::clone(&theme_condvar_main_pair);
+        let (listener, _socket_lock) = acquire_socket(&socket_path).unwrap().unwrap();
+        let _client = UnixStream::connect(&socket_path).unwrap();
+        assert!(listener.accept().is_ok());
+        let _ = std::fs::remove_dir_all(directory);
+    }
 
-            // Listening to incoming connections
-            thread::spawn(move || {
-                for stream in listener.incoming() {
-                    match stream {
-                        Ok(stream) => {
-                            let theme_client_subscriber = theme_condvar_sub_pair.clone();
-                            thread::spawn(move || {
-                                handle_connect(publisher, theme_client_subscriber, stream);
-                            });
-                        }
-                        Err(_) => {
-                            panic!("Stream error");
-                        }
-                    }
-                }
-            });
+    #[test]
+    fn keep_locked_socket() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("locked");
+        let socket_path = directory.join("theme-listener.sock");
+        let _socket_lock = lock(&socket_path).unwrap().unwrap();
 
-            listen_theme(publisher, theme_condvar_pub_pair);
-            return Ok(());
-        } else if std::env::args().any(|args| args == "-d") {
-            let process_id = fork();
-            // If parent process then terminate
-            if process_id != 0 {
-                return Ok(());
-            }
+        // Another -init is starting the daemon
+        assert!(acquire_socket(&socket_path).unwrap().is_none());
+        assert!(!exists(&socket_path).unwrap());
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn configured_listeners_run_in_one_place() {
+        let mut config = Config {
+            listeners: vec!["tmux".to_string()],
+            ..Config::default()
+        };
+        // A plain -init hosts nothing, the -d client applies the themes
+        assert!(listener_names(&config, true).is_empty());
+        assert_eq!(listener_names(&config, false), ["tmux"]);
+
+        config.host_listeners = true;
+        assert_eq!(listener_names(&config, true), ["tmux"]);
+        assert!(listener_names(&config, false).is_empty());
+    }
+
+    #[test]
+    fn skip_listeners_that_fail_to_build() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("listeners");
+        for theme in ["light", "dark"] {
+            std::fs::write(directory.join(format!("{theme}.config")), "").unwrap();
         }
+        let mut config = Config {
+            listeners: vec!["alacritty".to_string(), "tmux".to_string()],
+            ..Config::default()
+        };
+        config.alacritty.theme_files.directory = Some(directory.join("missing"));
+        config.tmux.theme_files.directory = Some(directory.clone());
+        assert!(!enabled_listeners(&config, false).unwrap().is_empty());
+
+        config.tmux.theme_files.directory = Some(directory.join("missing"));
+        assert!(matches!(
+            enabled_listeners(&config, false),
+            Err(Error::Config(_))
+        ));
+        let _ = std::fs::remove_dir_all(directory);
+    }
 
-        if std::env::args().any(|args| args == "-alacritty") {
-            let alacritty = Alacritty::new();
-            handle_stream(alacritty);
-        } else if std::env::args().any(|args| args == "-tmux") {
-            let tmux = Tmux::new();
-            handle_stream(tmux);
+    #[test]
+    fn reload_keeps_config_when_a_listener_breaks() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("reload");
+        for theme in ["light", "dark", "dim"] {
+            std::fs::write(directory.join(format!("{theme}.config")), "").unwrap();
         }
+        let config_path = directory.join("config.toml");
+        let write_config = |tmux_themes: &str| {
+            let content = format!(
+                "host_listeners = true\nlisteners = [\"tmux\"]\n[tmux]\ndirectory = {:?}\n[tmux.themes]\n{tmux_themes}\n",
+                directory.display().to_string()
+            );
+            std::fs::write(&config_path, content).unwrap();
+        };
+        write_config("");
+        let config_file = ConfigFile::new(Some(config_path.clone()));
+        let mut config = config_file.load().unwrap();
+        let listeners = Mutex::new(enabled_listeners(&config, true).unwrap());
+        let event = ThemeEvent::new(ThemeState::new(Theme::Light));
+        let state = DaemonState::new(event, Theme::Light, Variants::default()).unwrap();
+
+        // The dark theme file no longer exists
+        write_config("dark = \"missing\"");
+        let loaded = config.clone();
+        assert!(matches!(
+            reload_config(&config_file, &mut config, &listeners, &state),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(config, loaded);
+
+        write_config("dim = \"dim\"");
+        reload_config(&config_file, &mut config, &listeners, &state).unwrap();
+        assert!(config.tmux.theme_files.themes.contains_key("dim"));
+
+        // Invalid files are rejected before any listener is built
+        std::fs::write(&config_path, "listeners = [\"kitty\"]\n").unwrap();
+        let loaded = config.clone();
+        assert!(reload_config(&config_file, &mut config, &listeners, &state).is_err());
+        assert_eq!(config, loaded);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn commands_ignore_broken_config() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("broken-config");
+        let config_path = directory.join("config.toml");
+        std::fs::write(&config_path, "listeners = [\"kitty\"]\n").unwrap();
+        let config_file = ConfigFile::new(Some(config_path));
 
-        Ok(())
+        assert!(matches!(
+            load_config(&config_file, false),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(load_config(&config_file, true).unwrap(), Config::default());
+        let _ = std::fs::remove_dir_all(directory);
     }
 }
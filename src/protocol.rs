@@ -0,0 +1,457 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+use crate::theme::{Theme, ThemeEvent};
+
+/**
+ * Latest JSON-lines protocol version understood by the daemon
+ */
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/**
+ * Features advertised to JSON-lines clients in the welcome message
+ */
+const CAPABILITIES: [&str; 6] = [
+    "events",
+    "accent-color",
+    "contrast",
+    "named-themes",
+    "shutdown",
+    "heartbeat",
+];
+
+/**
+ * How often subscribers that asked for heartbeats receive one
+ */
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/**
+ * Longest duration an override may be set for
+ */
+pub const MAX_DURATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/**
+ * Parses a duration such as "90", "90s", "30m" or "2h", up to MAX_DURATION
+ */
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let invalid = || Error::Parse(format!("invalid duration {value:?}"));
+    let (amount, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(invalid()),
+    };
+    amount
+        .checked_mul(unit_seconds)
+        .map(Duration::from_secs)
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| {
+            Error::Parse(format!(
+                "duration {value:?} exceeds {} days",
+                MAX_DURATION.as_secs() / (24 * 60 * 60)
+            ))
+        })
+}
+
+/**
+ * Plain-text commands a client can send as its first line
+ */
+#[derive(Debug)]
+pub enum Command {
+    /// Reply with the current theme event and close
+    Get,
+    /// Stream theme events, same as sending nothing. With true heartbeat
+    /// lines are sent in between
+    Subscribe(bool),
+    /// Override the theme, optionally for a limited time. Named themes must
+    /// be declared in the listener themes of the config
+    Set(Theme, Option<Duration>),
+    /// Override the theme with the opposite of the current one, no
+    /// preference counts as the configured policy. Named themes have no
+    /// opposite and are rejected
+    Toggle(Option<Duration>),
+    /// Drop the override and follow the OS theme again
+    Clear,
+    /// Reply with the current theme event and daemon information
+    Status,
+    /// Reply with pong
+    Ping,
+    /// Shut the daemon down
+    Stop,
+}
+
+/**
+ * To convert a command line sent by a client to Command
+ */
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Command> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        match parts.as_slice() {
+            ["get"] => Ok(Command::Get),
+            ["subscribe"] => Ok(Command::Subscribe(false)),
+            ["subscribe", "heartbeat"] => Ok(Command::Subscribe(true)),
+            ["set", theme] => Ok(Command::Set(theme.parse()?, None)),
+            ["set", theme, duration] => Ok(Command::Set(
+                theme.parse()?,
+                Some(parse_duration(duration)?),
+            )),
+            ["toggle"] => Ok(Command::Toggle(None)),
+            ["toggle", duration] => Ok(Command::Toggle(Some(parse_duration(duration)?))),
+            ["clear"] => Ok(Command::Clear),
+            ["status"] => Ok(Command::Status),
+            ["ping"] => Ok(Command::Ping),
+            ["stop"] => Ok(Command::Stop),
+            _ => Err(Error::Parse(format!("invalid command {value:?}"))),
+        }
+    }
+}
+
+/**
+ * Messages sent by JSON-lines clients
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+        /// Whether to receive heartbeat messages
+        #[serde(default)]
+        heartbeat: bool,
+    },
+}
+
+impl ClientMessage {
+    /**
+     * Serialises the message as a single JSON line without the newline
+     */
+    pub fn to_line(&self) -> String {
+        // Serialising plain strings and numbers cannot fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/**
+ * Messages sent by the daemon to JSON-lines clients
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ServerMessage {
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+        /// Seconds between heartbeats, only when they were asked for
+        #[serde(skip_serializing_if = "Option::is_none")]
+        heartbeat_interval: Option<u64>,
+    },
+    Event {
+        sequence: u64,
+        /// Milliseconds since the UNIX epoch
+        timestamp: u64,
+        source: String,
+        theme: String,
+        accent_color: Option<String>,
+        contrast: String,
+    },
+    Error {
+        message: String,
+    },
+    /// The daemon is stopping and closes the connection
+    Shutdown,
+    /// Sent every HEARTBEAT_INTERVAL to show the daemon is alive
+    Heartbeat,
+}
+
+impl ServerMessage {
+    /**
+     * Builds the welcome reply for the version requested by a client
+     * @param version The version sent in the client hello
+     * @param heartbeat Whether the client asked for heartbeats
+     */
+    pub fn welcome(version: u32, heartbeat: bool) -> Result<ServerMessage> {
+        if version == 0 {
+            return Err(Error::Parse(format!(
+                "unsupported protocol version {version}"
+            )));
+        }
+        Ok(ServerMessage::Welcome {
+            version: version.min(PROTOCOL_VERSION),
+            capabilities: CAPABILITIES.map(String::from).to_vec(),
+            heartbeat_interval: heartbeat.then_some(HEARTBEAT_INTERVAL.as_secs()),
+        })
+    }
+
+    pub fn event(event: &ThemeEvent) -> ServerMessage {
+        ServerMessage::Event {
+            sequence: event.sequence,
+            timestamp: event
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            source: event.source.to_string(),
+            theme: event.state.theme.to_string(),
+            accent_color: event.state.accent_color.map(|color| color.to_string()),
+            contrast: event.state.contrast.to_string(),
+        }
+    }
+
+    /**
+     * Serialises the message as a single JSON line without the newline
+     */
+    pub fn to_line(&self) -> String {
+        // Serialising plain strings and numbers cannot fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/**
+ * The wire format a client talks in, picked by its first message.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Legacy clients that never send anything receive plain-text lines
+    /// holding only the theme
+    Text,
+    /// Clients that sent a hello receive JSON-lines messages
+    Json,
+}
+
+impl Protocol {
+    /**
+     * Encodes a theme event for this protocol without the trailing newline.
+     * Text subscribers such as the Neovim integration expect a bare light or
+     * dark line, so no preference is resolved for them. The other attributes
+     * and the unresolved theme are only sent as JSON.
+     * @param event The event to encode
+     * @param no_preference What text subscribers receive for no preference
+     */
+    pub fn encode_event(self, event: &ThemeEvent, no_preference: &Theme) -> String {
+        match self {
+            Protocol::Text => event.state.theme.resolve(no_preference).to_string(),
+            Protocol::Json => ServerMessage::event(event).to_line(),
+        }
+    }
+
+    /**
+     * Encodes a heartbeat for this protocol without the trailing newline.
+     * No theme may be named heartbeat, so the line cannot be mistaken for a
+     * text event.
+     */
+    pub fn encode_heartbeat(self) -> String {
+        match self {
+            Protocol::Text => "heartbeat".to_string(),
+            Protocol::Json => ServerMessage::Heartbeat.to_line(),
+        }
+    }
+}
+
+/**
+ * To convert a JSON line sent by a client to ClientMessage
+ */
+impl FromStr for ClientMessage {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<ClientMessage> {
+        serde_json::from_str(value)
+            .map_err(|error| Error::Parse(format!("invalid client message {value:?}: {error}")))
+    }
+}
+
+/**
+ * To convert a JSON line sent by the daemon to ServerMessage
+ */
+impl FromStr for ServerMessage {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<ServerMessage> {
+        serde_json::from_str(value)
+            .map_err(|error| Error::Parse(format!("invalid server message {value:?}: {error}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theme::{Contrast, ThemeState};
+
+    #[test]
+    fn text_events_hold_only_the_theme() {
+        let mut state = ThemeState::new(Theme::Light);
+        state.accent_color = "#3584e4".parse().ok();
+        state.contrast = Contrast::High;
+        assert_eq!(
+            Protocol::Text.encode_event(&ThemeEvent::new(state), &Theme::Dark),
+            "light"
+        );
+        assert_eq!(Protocol::Text.encode_heartbeat(), "heartbeat");
+    }
+
+    #[test]
+    fn text_events_resolve_no_preference() {
+        let event = ThemeEvent::new(ThemeState::new(Theme::NoPreference));
+        assert_eq!(Protocol::Text.encode_event(&event, &Theme::Dark), "dark");
+        assert_eq!(Protocol::Text.encode_event(&event, &Theme::Light), "light");
+
+        let line = Protocol::Json.encode_event(&event, &Theme::Dark);
+        assert!(matches!(
+            line.parse(),
+            Ok(ServerMessage::Event { theme, .. }) if theme == "no-preference"
+        ));
+    }
+
+    #[test]
+    fn parse_client_message() {
+        assert!(matches!(
+            r#"{"type":"hello","version":1}"#.parse(),
+            Ok(ClientMessage::Hello {
+                version: 1,
+                heartbeat: false
+            })
+        ));
+        let hello = ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            heartbeat: true,
+        };
+        assert!(matches!(
+            hello.to_line().parse(),
+            Ok(ClientMessage::Hello {
+                heartbeat: true,
+                ..
+            })
+        ));
+        for value in ["", "hello", r#"{"type":"bye"}"#, r#"{"type":"hello"}"#] {
+            assert!(
+                matches!(value.parse::<ClientMessage>(), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_server_message() {
+        let mut state = ThemeState::new(Theme::Dark);
+        state.contrast = Contrast::High;
+        let line = ServerMessage::event(&ThemeEvent::new(state)).to_line();
+        assert!(matches!(
+            line.parse(),
+            Ok(ServerMessage::Event { sequence: 1, theme, accent_color: None, contrast, .. })
+                if theme == "dark" && contrast == "high"
+        ));
+        assert!(matches!(
+            r#"{"type":"shutdown"}"#.parse(),
+            Ok(ServerMessage::Shutdown)
+        ));
+        assert!(matches!("".parse::<ServerMessage>(), Err(Error::Parse(_))));
+        assert!(matches!(
+            "dark".parse::<ServerMessage>(),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn welcome_version() {
+        assert!(matches!(
+            ServerMessage::welcome(PROTOCOL_VERSION + 1, false),
+            Ok(ServerMessage::Welcome {
+                version: PROTOCOL_VERSION,
+                heartbeat_interval: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            ServerMessage::welcome(0, true),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn parse_command() {
+        assert!(matches!("subscribe".parse(), Ok(Command::Subscribe(false))));
+        assert!(matches!(
+            "subscribe heartbeat".parse(),
+            Ok(Command::Subscribe(true))
+        ));
+        assert!(matches!("get".parse(), Ok(Command::Get)));
+        assert!(matches!(
+            "set dark".parse(),
+            Ok(Command::Set(Theme::Dark, None))
+        ));
+        assert!(matches!(
+            "set dim 2h".parse(),
+            Ok(Command::Set(Theme::Named(name), Some(duration)))
+                if name == "dim" && duration == Duration::from_secs(7200)
+        ));
+        assert!(matches!("toggle".parse(), Ok(Command::Toggle(None))));
+        assert!(matches!(
+            "toggle 90".parse(),
+            Ok(Command::Toggle(Some(duration))) if duration == Duration::from_secs(90)
+        ));
+        assert!(matches!("clear".parse(), Ok(Command::Clear)));
+        assert!(matches!("status".parse(), Ok(Command::Status)));
+        assert!(matches!("ping".parse(), Ok(Command::Ping)));
+        assert!(matches!("stop".parse(), Ok(Command::Stop)));
+    }
+
+    #[test]
+    fn parse_invalid_command() {
+        // An empty line is what a closed connection reads as
+        for value in [
+            "",
+            "\n",
+            "set",
+            "set heartbeat",
+            "set dark 2d",
+            "set dark 2h extra",
+            "toggle forever",
+            "get now",
+            "restart",
+        ] {
+            assert!(
+                matches!(value.parse::<Command>(), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("720h").unwrap(), MAX_DURATION);
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        for value in ["", "s", "-5", "1.5h", "2d", "10 s", "2H"] {
+            assert!(
+                matches!(parse_duration(value), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_overflowing_durations() {
+        for value in [
+            "721h",
+            "18446744073709551615",
+            "18446744073709551616",
+            "5124095576030432h",
+        ] {
+            assert!(
+                matches!(parse_duration(value), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+}
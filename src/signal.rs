@@ -0,0 +1,134 @@
+use crate::error::{Error, Result};
+
+use libc::{c_int, c_void, sigaction, SA_RESTART};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::{mem, ptr};
+
+// Write end of the self-pipe the signal handler reports to
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+// sigaction definition and errno location specific to os
+cfg_if::cfg_if!(
+    if #[cfg(target_os = "linux")] {
+        unsafe fn signal_action() -> sigaction {
+            sigaction {
+                sa_sigaction: handle_signal as *const () as usize,
+                sa_flags: SA_RESTART,
+                sa_restorer: None,
+                sa_mask: mem::zeroed(),
+            }
+        }
+
+        unsafe fn errno() -> *mut c_int {
+            libc::__errno_location()
+        }
+    } else if #[cfg(target_os = "macos")] {
+        unsafe fn signal_action() -> sigaction {
+            sigaction {
+                sa_sigaction: handle_signal as *const () as usize,
+                sa_flags: SA_RESTART,
+                sa_mask: mem::zeroed(),
+            }
+        }
+
+        unsafe fn errno() -> *mut c_int {
+            libc::__error()
+        }
+    }
+);
+
+/**
+ * Hands the signal over to the thread blocked in SignalPipe::wait. Only
+ * async-signal-safe calls are allowed here, so it writes a single byte and
+ * restores errno for the code it interrupted.
+ */
+extern "C" fn handle_signal(signal: c_int) {
+    unsafe {
+        let saved_errno = *errno();
+        let byte = signal as u8;
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            ptr::from_ref(&byte).cast::<c_void>(),
+            1,
+        );
+        *errno() = saved_errno;
+    }
+}
+
+/**
+ * Ends the process after it shut down. Unlike std::process::exit no exit
+ * handlers run, which could block on locks held by the remaining threads.
+ * @param status The exit status
+ */
+pub fn exit_immediately(status: c_int) -> ! {
+    let _ = io::stdout().flush();
+    unsafe { libc::_exit(status) }
+}
+
+/**
+ * Read end of the self-pipe receiving the handled signals.
+ */
+pub struct SignalPipe(File);
+
+impl SignalPipe {
+    /**
+     * Routes the given signals to a new self-pipe.
+     * @param signals The signals to handle, e.g. SIGTERM
+     */
+    pub fn install(signals: &[c_int]) -> Result<SignalPipe> {
+        let signal_error =
+            |error: io::Error| Error::Ipc("Error installing signal handler".to_string(), error);
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(signal_error(io::Error::last_os_error()));
+        }
+        let [read, write] = fds;
+        unsafe {
+            libc::fcntl(read, libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(write, libc::F_SETFD, libc::FD_CLOEXEC);
+            // A full pipe already has a wake-up pending, the handler must never block
+            libc::fcntl(write, libc::F_SETFL, libc::O_NONBLOCK);
+        }
+        SIGNAL_PIPE.store(write, Ordering::Relaxed);
+
+        let action = unsafe { signal_action() };
+        for signal in signals {
+            if unsafe { sigaction(*signal, &action, ptr::null_mut()) } == -1 {
+                return Err(signal_error(io::Error::last_os_error()));
+            }
+        }
+        Ok(SignalPipe(unsafe { File::from_raw_fd(read) }))
+    }
+
+    /**
+     * Blocks until one of the signals is received.
+     * @return The signal number
+     */
+    pub fn wait(&mut self) -> Result<c_int> {
+        let mut signal = [0; 1];
+        self.0
+            .read_exact(&mut signal)
+            .map_err(|error| Error::Ipc("Error reading signal pipe".to_string(), error))?;
+        Ok(c_int::from(signal[0]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_reach_the_pipe() {
+        let mut signals = SignalPipe::install(&[libc::SIGUSR1, libc::SIGUSR2]).unwrap();
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+            libc::raise(libc::SIGUSR1);
+        }
+        assert_eq!(signals.wait().unwrap(), libc::SIGUSR2);
+        assert_eq!(signals.wait().unwrap(), libc::SIGUSR1);
+    }
+}
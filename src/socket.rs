@@ -0,0 +1,373 @@
+use crate::error::{Error, Result};
+
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+// Environment variable overriding the socket location
+const SOCKET_ENV: &str = "THEME_LISTENER_SOCKET";
+
+/**
+ * The per-user directory holding the socket when no override is given.
+ */
+fn default_socket_directory() -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(runtime_directory) if !runtime_directory.is_empty() => {
+            PathBuf::from(runtime_directory).join("theme-listener")
+        }
+        _ => {
+            let uid = unsafe { libc::getuid() };
+            std::env::temp_dir().join(format!("theme-listener-{uid}"))
+        }
+    }
+}
+
+/**
+ * Resolves the daemon socket location shared by the daemon and its clients.
+ * The -socket flag wins over THEME_LISTENER_SOCKET, then the config file and
+ * then $XDG_RUNTIME_DIR/theme-listener/theme-listener.sock. Without a runtime
+ * directory a per-user directory in the temporary directory is used.
+ * @param socket_flag The value passed with -socket, if any
+ * @param configured The socket set in the config file, if any
+ */
+pub fn socket_path(socket_flag: Option<PathBuf>, configured: Option<PathBuf>) -> PathBuf {
+    if let Some(socket_path) = socket_flag {
+        return socket_path;
+    }
+    if let Some(socket_path) = std::env::var_os(SOCKET_ENV)
+        .map(PathBuf::from)
+        .or(configured)
+    {
+        // Detached processes chdir to / so resolve relative paths up front
+        return std::path::absolute(&socket_path).unwrap_or(socket_path);
+    }
+    default_socket_directory().join("theme-listener.sock")
+}
+
+/**
+ * Checks that only the current user can reach the sockets in a directory,
+ * the default one may have been created by someone else in /tmp.
+ */
+fn check_socket_directory(socket_directory: &Path) -> Result<()> {
+    let metadata = socket_directory.symlink_metadata().map_err(|error| {
+        Error::Ipc(
+            format!("Error reading {}", socket_directory.display()),
+            error,
+        )
+    })?;
+    if !metadata.is_dir() || metadata.uid() != unsafe { libc::getuid() } {
+        return Err(Error::Config(format!(
+            "Socket directory {} is not a directory owned by the current user",
+            socket_directory.display()
+        )));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(Error::Config(format!(
+            "Socket directory {} is accessible by other users, expected mode 0700",
+            socket_directory.display()
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Creates a missing socket directory with 0700 permissions. The default
+ * directory must be private to the current user.
+ */
+fn create_socket_directory(socket_path: &Path) -> Result<()> {
+    if let Some(socket_directory) = socket_path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(socket_directory)
+            .map_err(|error| {
+                Error::Ipc(
+                    format!("Error creating {}", socket_directory.display()),
+                    error,
+                )
+            })?;
+
+        // Never trust a shared directory such as /tmp with the default socket
+        if socket_directory == default_socket_directory() {
+            check_socket_directory(socket_directory)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Takes the lock guarding the socket so only one -init binds it at a time.
+ * The lock is held until the returned file is closed, which for the daemon
+ * is when it exits.
+ * @param socket_path The socket the lock belongs to
+ * @return None when another process holds the lock
+ */
+pub fn lock(socket_path: &Path) -> Result<Option<File>> {
+    create_socket_directory(socket_path)?;
+
+    let lock_path = socket_path.with_extension("lock");
+    let lock_error =
+        |error: io::Error| Error::Ipc(format!("Error locking {}", lock_path.display()), error);
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(&lock_path)
+        .map_err(lock_error)?;
+
+    if unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = io::Error::last_os_error();
+        if error.kind() == io::ErrorKind::WouldBlock {
+            return Ok(None);
+        }
+        return Err(lock_error(error));
+    }
+    Ok(Some(lock_file))
+}
+
+/**
+ * Whether a daemon holds the lock of the socket, which it does until it
+ * exits. Unlike a pidfile this cannot point at an unrelated process.
+ * @param socket_path The socket the lock belongs to
+ */
+pub fn is_locked(socket_path: &Path) -> bool {
+    let Ok(lock_file) = File::open(socket_path.with_extension("lock")) else {
+        return false;
+    };
+    // Taking the lock succeeds when nobody holds it, it is released on drop
+    let result = unsafe { libc::flock(lock_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    result != 0 && io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock
+}
+
+/**
+ * Binds the daemon socket with 0600 permissions.
+ * @param socket_path The socket to bind
+ */
+pub fn bind(socket_path: &Path) -> Result<UnixListener> {
+    // Restrict the socket from the moment it is created
+    let previous_umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket_path);
+    unsafe { libc::umask(previous_umask) };
+
+    listener.map_err(|error| {
+        Error::Ipc(
+            "Error while starting the listener server".to_string(),
+            error,
+        )
+    })
+}
+
+/**
+ * Credentials of the process on the other end of a socket.
+ */
+struct PeerCredentials {
+    pid: Option<libc::pid_t>,
+    uid: libc::uid_t,
+}
+
+cfg_if::cfg_if!(
+    if #[cfg(target_os = "linux")] {
+        fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+            unsafe {
+                let mut credentials: libc::ucred = mem::zeroed();
+                let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+                let result = libc::getsockopt(
+                    stream.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PEERCRED,
+                    &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                    &mut length,
+                );
+                if result != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(PeerCredentials {
+                    pid: Some(credentials.pid),
+                    uid: credentials.uid,
+                })
+            }
+        }
+    } else if #[cfg(target_os = "macos")] {
+        fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+            unsafe {
+                let mut uid: libc::uid_t = mem::zeroed();
+                let mut gid: libc::gid_t = mem::zeroed();
+                if libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(PeerCredentials { pid: None, uid })
+            }
+        }
+    }
+);
+
+/**
+ * The PID of the process on the other end of a socket, where the OS reports
+ * it.
+ */
+pub fn peer_pid(stream: &UnixStream) -> Option<libc::pid_t> {
+    peer_credentials(stream).ok()?.pid
+}
+
+/**
+ * Checks that an accepted client runs as the same user as the daemon,
+ * logging rejected peers.
+ * @param stream The accepted client
+ */
+pub fn is_authorized(stream: &UnixStream) -> bool {
+    let credentials = match peer_credentials(stream) {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            println!("WARNING: Rejected connection, peer credentials unavailable: {error}");
+            return false;
+        }
+    };
+
+    let uid = unsafe { libc::getuid() };
+    if credentials.uid != uid {
+        let pid = credentials
+            .pid
+            .map_or("unknown".to_string(), |pid| pid.to_string());
+        println!(
+            "WARNING: Rejected connection from pid {pid} running as uid {}",
+            credentials.uid
+        );
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn socket_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("theme-listener-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn socket_path_precedence() {
+        let _env = crate::lock_env();
+        let flag = PathBuf::from("/run/flag.sock");
+        let configured = PathBuf::from("/run/configured.sock");
+        std::env::set_var(SOCKET_ENV, "/run/env.sock");
+        assert_eq!(
+            socket_path(Some(flag.clone()), Some(configured.clone())),
+            flag
+        );
+        assert_eq!(
+            socket_path(None, Some(configured.clone())),
+            PathBuf::from("/run/env.sock")
+        );
+
+        std::env::remove_var(SOCKET_ENV);
+        assert_eq!(socket_path(None, Some(configured.clone())), configured);
+        assert_eq!(
+            socket_path(None, None),
+            default_socket_directory().join("theme-listener.sock")
+        );
+    }
+
+    #[test]
+    fn default_socket_directory_per_user() {
+        let _env = crate::lock_env();
+        let runtime_directory = std::env::var_os("XDG_RUNTIME_DIR");
+        std::env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
+        assert_eq!(
+            default_socket_directory(),
+            PathBuf::from("/run/user/1000/theme-listener")
+        );
+
+        std::env::remove_var("XDG_RUNTIME_DIR");
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(
+            default_socket_directory(),
+            std::env::temp_dir().join(format!("theme-listener-{uid}"))
+        );
+        if let Some(runtime_directory) = runtime_directory {
+            std::env::set_var("XDG_RUNTIME_DIR", runtime_directory);
+        }
+    }
+
+    #[test]
+    fn private_socket_and_directory() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("private");
+        let socket_path = directory.join("theme-listener.sock");
+        let _socket_lock = lock(&socket_path).unwrap().unwrap();
+        let _listener = bind(&socket_path).unwrap();
+
+        let mode = |path: &Path| path.metadata().unwrap().mode() & 0o777;
+        assert_eq!(mode(&directory), 0o700);
+        assert_eq!(mode(&socket_path), 0o600);
+        assert_eq!(mode(&socket_path.with_extension("lock")), 0o600);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn reject_shared_socket_directory() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("shared");
+        DirBuilder::new().mode(0o700).create(&directory).unwrap();
+        assert!(check_socket_directory(&directory).is_ok());
+
+        set_permissions(&directory, Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(
+            check_socket_directory(&directory),
+            Err(Error::Config(_))
+        ));
+
+        // A link planted in its place is rejected even when it leads to a
+        // private directory
+        let link = directory.with_extension("link");
+        set_permissions(&directory, Permissions::from_mode(0o700)).unwrap();
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&directory, &link).unwrap();
+        assert!(matches!(
+            check_socket_directory(&link),
+            Err(Error::Config(_))
+        ));
+        let _ = std::fs::remove_file(link);
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn authorize_same_user() {
+        let (daemon_end, client_end) = UnixStream::pair().unwrap();
+        assert!(is_authorized(&daemon_end));
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                peer_pid(&client_end),
+                Some(std::process::id() as libc::pid_t)
+            );
+        }
+    }
+
+    #[test]
+    fn lock_held_by_daemon() {
+        let _env = crate::lock_env();
+        let directory = socket_directory("lock");
+        let socket_path = directory.join("theme-listener.sock");
+        assert!(!is_locked(&socket_path));
+
+        let socket_lock = lock(&socket_path).unwrap().unwrap();
+        assert!(is_locked(&socket_path));
+        assert!(lock(&socket_path).unwrap().is_none());
+
+        // The lock goes away with the daemon, the file stays
+        drop(socket_lock);
+        assert!(!is_locked(&socket_path));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
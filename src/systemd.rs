@@ -0,0 +1,290 @@
+use crate::error::{Error, Result};
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// First file descriptor passed with socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+// NOTIFY_SOCKET, taken out of the environment on first use
+static NOTIFY_SOCKET: OnceLock<Option<OsString>> = OnceLock::new();
+
+// Abstract socket addresses only exist on Linux
+cfg_if::cfg_if!(
+    if #[cfg(target_os = "linux")] {
+        fn notify_address(notify_socket: &OsStr) -> io::Result<SocketAddr> {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::ffi::OsStrExt;
+
+            // Abstract socket names are passed with a leading @
+            match notify_socket.as_bytes().strip_prefix(b"@") {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(notify_socket),
+            }
+        }
+    } else if #[cfg(target_os = "macos")] {
+        fn notify_address(notify_socket: &OsStr) -> io::Result<SocketAddr> {
+            SocketAddr::from_pathname(notify_socket)
+        }
+    }
+);
+
+/**
+ * Reads a variable systemd only meant for this process, e.g. LISTEN_FDS
+ * next to LISTEN_PID.
+ */
+fn env_for_process(key: &str, pid_key: &str) -> Option<String> {
+    if let Ok(pid) = std::env::var(pid_key) {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    std::env::var(key).ok()
+}
+
+/**
+ * Takes the listening socket passed with systemd socket activation.
+ * @return None when the daemon was not socket activated
+ */
+pub fn activated_listener() -> Result<Option<UnixListener>> {
+    // LISTEN_PID is required, the variables may have been inherited otherwise
+    let listen_fds = match std::env::var_os("LISTEN_PID") {
+        Some(_) => env_for_process("LISTEN_FDS", "LISTEN_PID"),
+        None => None,
+    };
+    let listen_fds: RawFd = listen_fds.and_then(|fds| fds.parse().ok()).unwrap_or(0);
+
+    // Processes started later must not take the sockets again
+    for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(key);
+    }
+
+    if listen_fds == 0 {
+        return Ok(None);
+    }
+    if listen_fds > 1 {
+        println!(
+            "WARNING: Ignoring {} extra sockets passed by systemd",
+            listen_fds - 1
+        );
+    }
+    take_listener(LISTEN_FDS_START).map(Some)
+}
+
+/**
+ * Takes ownership of a listening stream socket passed by systemd.
+ * @param fd The file descriptor of the socket
+ */
+fn take_listener(fd: RawFd) -> Result<UnixListener> {
+    let mut socket_type: libc::c_int = 0;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut socket_type as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 || socket_type != libc::SOCK_STREAM {
+        return Err(Error::Config(
+            "Socket passed by systemd is not a stream socket".to_string(),
+        ));
+    }
+
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        Ok(UnixListener::from_raw_fd(fd))
+    }
+}
+
+/**
+ * The socket systemd listens on for state changes. The variable is removed
+ * the first time so processes started later do not notify systemd too.
+ */
+fn notify_socket() -> Option<&'static OsStr> {
+    NOTIFY_SOCKET
+        .get_or_init(|| {
+            let notify_socket = std::env::var_os("NOTIFY_SOCKET");
+            std::env::remove_var("NOTIFY_SOCKET");
+            notify_socket
+        })
+        .as_deref()
+}
+
+/**
+ * Whether systemd waits for the daemon to report READY=1.
+ */
+pub fn is_notify_service() -> bool {
+    notify_socket().is_some()
+}
+
+/**
+ * Sends state changes such as READY=1 to systemd, doing nothing when the
+ * daemon does not run as a notify service.
+ * @param state Newline separated assignments, e.g. "READY=1\nSTATUS=..."
+ */
+pub fn notify(state: &str) -> Result<()> {
+    match notify_socket() {
+        Some(notify_socket) => send_state(notify_socket, state),
+        None => Ok(()),
+    }
+}
+
+/**
+ * Sends a single datagram holding the state to the notify socket.
+ * @param notify_socket A socket path, or an abstract name starting with @
+ * @param state Newline separated assignments
+ */
+fn send_state(notify_socket: &OsStr, state: &str) -> Result<()> {
+    let notify_error = |error: io::Error| Error::Ipc("Error notifying systemd".to_string(), error);
+
+    let address = notify_address(notify_socket).map_err(notify_error)?;
+    let socket = UnixDatagram::unbound().map_err(notify_error)?;
+    socket
+        .send_to_addr(state.as_bytes(), &address)
+        .map_err(notify_error)?;
+    Ok(())
+}
+
+/**
+ * How long systemd waits for a WATCHDOG=1 keepalive before it considers
+ * the daemon hung, None when the watchdog is disabled. The variables are
+ * removed, only call it once.
+ */
+pub fn watchdog_timeout() -> Option<Duration> {
+    let watchdog_usec = env_for_process("WATCHDOG_USEC", "WATCHDOG_PID");
+    for key in ["WATCHDOG_USEC", "WATCHDOG_PID"] {
+        std::env::remove_var(key);
+    }
+    let watchdog_usec: u64 = watchdog_usec?.parse().ok()?;
+    (watchdog_usec > 0).then(|| Duration::from_micros(watchdog_usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::fd::{AsRawFd, IntoRawFd};
+    use std::path::PathBuf;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("theme-listener-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 256];
+        let length = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..length]).into_owned()
+    }
+
+    #[test]
+    fn notify_sends_state_and_removes_variable() {
+        let _env = crate::lock_env();
+        let path = socket_path("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+
+        assert!(is_notify_service());
+        assert!(std::env::var_os("NOTIFY_SOCKET").is_none());
+        notify("READY=1\nSTATUS=Theme dark, 0 clients").unwrap();
+        assert_eq!(receive(&socket), "READY=1\nSTATUS=Theme dark, 0 clients");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn send_state_to_path() {
+        let _env = crate::lock_env();
+        let path = socket_path("path");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        send_state(path.as_os_str(), "WATCHDOG=1").unwrap();
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn send_state_to_abstract_name() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("theme-listener-{}-abstract", std::process::id());
+        let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&address).unwrap();
+        send_state(OsStr::new(&format!("@{name}")), "STOPPING=1").unwrap();
+        assert_eq!(receive(&socket), "STOPPING=1");
+    }
+
+    #[test]
+    fn send_state_without_listener() {
+        let _env = crate::lock_env();
+        let path = socket_path("missing");
+        assert!(send_state(path.as_os_str(), "READY=1").is_err());
+    }
+
+    #[test]
+    fn activated_listener_for_other_process() {
+        let _env = crate::lock_env();
+        std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+        std::env::set_var("LISTEN_FDS", "1");
+        std::env::set_var("LISTEN_FDNAMES", "theme-listener.socket");
+
+        assert!(activated_listener().unwrap().is_none());
+        for key in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            assert!(std::env::var_os(key).is_none(), "{key} was not removed");
+        }
+    }
+
+    #[test]
+    fn activated_listener_without_variables() {
+        let _env = crate::lock_env();
+        std::env::set_var("LISTEN_FDS", "1");
+        assert!(activated_listener().unwrap().is_none());
+        assert!(std::env::var_os("LISTEN_FDS").is_none());
+    }
+
+    #[test]
+    fn take_stream_listener() {
+        let _env = crate::lock_env();
+        let path = socket_path("listener");
+        let fd = UnixListener::bind(&path).unwrap().into_raw_fd();
+        let listener = take_listener(fd).unwrap();
+        assert_eq!(listener.local_addr().unwrap().as_pathname(), Some(&*path));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn take_datagram_socket() {
+        let socket = UnixDatagram::unbound().unwrap();
+        assert!(take_listener(socket.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn watchdog_timeout_for_process() {
+        let _env = crate::lock_env();
+        std::env::set_var("WATCHDOG_USEC", "30000000");
+        std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+        assert_eq!(watchdog_timeout(), Some(Duration::from_secs(30)));
+        assert!(std::env::var_os("WATCHDOG_USEC").is_none());
+        assert!(std::env::var_os("WATCHDOG_PID").is_none());
+
+        std::env::set_var("WATCHDOG_USEC", "30000000");
+        std::env::set_var("WATCHDOG_PID", (std::process::id() + 1).to_string());
+        assert_eq!(watchdog_timeout(), None);
+
+        std::env::set_var("WATCHDOG_USEC", "0");
+        assert_eq!(watchdog_timeout(), None);
+        assert!(std::env::var_os("WATCHDOG_USEC").is_none());
+    }
+}
@@ -1,18 +1,49 @@
-#[derive(Clone, Copy)]
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+
+/**
+ * Light and dark are built in and mapped from the OS signal, any other
+ * variant ("dim", "sepia", ...) is user defined and carried by name.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Theme {
-    LIGHT,
-    DARK,
+    Light,
+    Dark,
+    NoPreference,
+    Named(String),
+}
+
+impl Theme {
+    /**
+     * Resolves a "no preference" theme to the given fallback, leaving every
+     * other variant untouched.
+     * @param no_preference The theme to use when no preference is set
+     */
+    pub fn resolve<'a>(&'a self, no_preference: &'a Theme) -> &'a Theme {
+        match self {
+            Theme::NoPreference => no_preference,
+            theme => theme,
+        }
+    }
 }
 
 impl std::fmt::Display for Theme {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Theme::LIGHT => {
+            Theme::Light => {
                 write!(f, "light")
             }
-            Theme::DARK => {
+            Theme::Dark => {
                 write!(f, "dark")
             }
+            Theme::NoPreference => {
+                write!(f, "no-preference")
+            }
+            Theme::Named(name) => {
+                write!(f, "{name}")
+            }
         }
     }
 }
@@ -20,10 +51,492 @@ impl std::fmt::Display for Theme {
 /**
  * To convert a string representation of theme value to Theme
  */
-pub fn to_theme(value: &str) -> Theme {
-    match value {
-        "light" => Theme::LIGHT,
-        "dark" => Theme::DARK,
-        _ => panic!("Invalid theme value to parse"),
+impl FromStr for Theme {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Theme> {
+        match value {
+            "light" => Ok(Theme::Light),
+            "dark" => Ok(Theme::Dark),
+            "no-preference" => Ok(Theme::NoPreference),
+            // Text subscribers receive bare heartbeat lines
+            "heartbeat" => Err(Error::Parse(
+                "heartbeat is not a valid theme name".to_string(),
+            )),
+            // Names end up in file names and the whitespace separated wire format
+            name if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Ok(Theme::Named(name.to_string()))
+            }
+            _ => Err(Error::Parse(format!("invalid theme value {value:?}"))),
+        }
+    }
+}
+
+impl TryFrom<&str> for Theme {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Theme> {
+        value.parse()
+    }
+}
+
+/**
+ * Reads the theme a listener should apply when the system reports no
+ * preference. The variable wins over the configured policy, defaults to
+ * light when neither is set.
+ * @param env_key The environment variable holding the policy
+ * @param configured The policy set in the config file, if any
+ */
+pub fn no_preference_policy(env_key: &str, configured: Option<&str>) -> Result<Theme> {
+    let (source, policy) = match std::env::var(env_key) {
+        Ok(policy) => (env_key, Some(policy)),
+        Err(_) => ("no_preference", configured.map(String::from)),
+    };
+    match policy.as_deref() {
+        None | Some("light") => Ok(Theme::Light),
+        Some("dark") => Ok(Theme::Dark),
+        Some(_) => Err(Error::Config(format!(
+            "{source} must be either light or dark"
+        ))),
+    }
+}
+
+/**
+ * An RGB accent color reported by the system.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccentColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl AccentColor {
+    /**
+     * Builds an accent color from channels in the 0.0 to 1.0 range.
+     * Returns None when any channel is out of range, which the portal uses
+     * to signal an unset accent color.
+     * @param channels The red, green and blue channels
+     */
+    pub fn from_channels(channels: &[f64]) -> Option<AccentColor> {
+        let [red, green, blue] = channels else {
+            return None;
+        };
+        let to_channel = |value: f64| {
+            if (0.0..=1.0).contains(&value) {
+                Some((value * 255.0).round() as u8)
+            } else {
+                None
+            }
+        };
+        Some(AccentColor {
+            red: to_channel(*red)?,
+            green: to_channel(*green)?,
+            blue: to_channel(*blue)?,
+        })
+    }
+}
+
+impl std::fmt::Display for AccentColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+/**
+ * To convert a "#rrggbb" representation of a color to AccentColor
+ */
+impl FromStr for AccentColor {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<AccentColor> {
+        let invalid = || Error::Parse(format!("invalid accent color value {value:?}"));
+        let hex = value
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6 && hex.is_ascii())
+            .ok_or_else(invalid)?;
+        let channel = |range: std::ops::Range<usize>| {
+            u8::from_str_radix(&hex[range], 16).map_err(|_| invalid())
+        };
+        Ok(AccentColor {
+            red: channel(0..2)?,
+            green: channel(2..4)?,
+            blue: channel(4..6)?,
+        })
+    }
+}
+
+/**
+ * The contrast preference reported by the system.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Contrast {
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::fmt::Display for Contrast {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Contrast::Normal => {
+                write!(f, "normal")
+            }
+            Contrast::High => {
+                write!(f, "high")
+            }
+        }
+    }
+}
+
+/**
+ * To convert a string representation of contrast value to Contrast
+ */
+impl FromStr for Contrast {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Contrast> {
+        match value {
+            "normal" => Ok(Contrast::Normal),
+            "high" => Ok(Contrast::High),
+            _ => Err(Error::Parse(format!("invalid contrast value {value:?}"))),
+        }
+    }
+}
+
+/**
+ * A single appearance setting that changed on the publisher side.
+ */
+#[derive(Clone, Debug)]
+pub enum ThemeUpdate {
+    Theme(Theme),
+    AccentColor(Option<AccentColor>),
+    Contrast(Contrast),
+    /// Every setting at once, e.g. after fetching the state again
+    State(ThemeState),
+}
+
+/**
+ * The full appearance state shared between the daemon and its clients.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThemeState {
+    pub theme: Theme,
+    pub accent_color: Option<AccentColor>,
+    pub contrast: Contrast,
+}
+
+impl ThemeState {
+    pub fn new(theme: Theme) -> ThemeState {
+        ThemeState {
+            theme,
+            accent_color: None,
+            contrast: Contrast::Normal,
+        }
+    }
+
+    /**
+     * Applies a publisher update on top of the current state
+     * @param update The setting that changed
+     */
+    pub fn apply(&mut self, update: ThemeUpdate) {
+        match update {
+            ThemeUpdate::Theme(theme) => self.theme = theme,
+            ThemeUpdate::AccentColor(accent_color) => self.accent_color = accent_color,
+            ThemeUpdate::Contrast(contrast) => self.contrast = contrast,
+            ThemeUpdate::State(state) => *self = state,
+        }
+    }
+}
+
+/**
+ * Wire representation: the theme followed by optional key=value attributes,
+ * e.g. "dark accent-color=#3584e4 contrast=high". Attributes holding their
+ * default value are left out.
+ */
+impl std::fmt::Display for ThemeState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.theme)?;
+        if let Some(accent_color) = self.accent_color {
+            write!(f, " accent-color={accent_color}")?;
+        }
+        if self.contrast != Contrast::Normal {
+            write!(f, " contrast={}", self.contrast)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+ * To convert a wire representation of the theme state to ThemeState.
+ * Unknown attributes are ignored so newer daemons can add them.
+ */
+impl FromStr for ThemeState {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<ThemeState> {
+        let mut parts = value.split_whitespace();
+        let mut state = ThemeState::new(parts.next().unwrap_or_default().parse()?);
+        for attribute in parts {
+            match attribute.split_once('=') {
+                Some(("accent-color", color)) => state.accent_color = Some(color.parse()?),
+                Some(("contrast", contrast)) => state.contrast = contrast.parse()?,
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+}
+
+/**
+ * Where a theme event originated from.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventSource {
+    /// The initial fetch when the daemon starts
+    Startup,
+    /// A change reported by the OS publisher, the desktop portal or AppKit
+    Portal,
+    /// A manual override being set, cleared or expiring
+    Override,
+}
+
+impl std::fmt::Display for EventSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventSource::Startup => {
+                write!(f, "startup")
+            }
+            EventSource::Portal => {
+                write!(f, "portal")
+            }
+            EventSource::Override => {
+                write!(f, "override")
+            }
+        }
+    }
+}
+
+/**
+ * A theme state change together with when, why and in which order it
+ * happened. Sequence numbers are monotonic for the lifetime of the daemon.
+ */
+#[derive(Clone, Debug)]
+pub struct ThemeEvent {
+    pub state: ThemeState,
+    pub sequence: u64,
+    pub timestamp: SystemTime,
+    pub source: EventSource,
+}
+
+impl ThemeEvent {
+    /**
+     * Creates the first event from the state fetched at startup
+     */
+    pub fn new(state: ThemeState) -> ThemeEvent {
+        ThemeEvent {
+            state,
+            sequence: 1,
+            timestamp: SystemTime::now(),
+            source: EventSource::Startup,
+        }
+    }
+
+    /**
+     * Advances to the next event when the state changed.
+     * @param state The new state
+     * @param source Where the change came from
+     * @return Whether a new event was produced
+     */
+    pub fn update(&mut self, state: ThemeState, source: EventSource) -> bool {
+        if state == self.state {
+            return false;
+        }
+
+        self.state = state;
+        self.sequence += 1;
+        self.timestamp = SystemTime::now();
+        self.source = source;
+        true
+    }
+}
+
+/**
+ * Wire representation: the theme state followed by the event attributes,
+ * e.g. "dark sequence=4 source=portal timestamp=1760000000123". The
+ * timestamp is in milliseconds since the UNIX epoch.
+ */
+impl std::fmt::Display for ThemeEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        write!(
+            f,
+            "{} sequence={} source={} timestamp={timestamp}",
+            self.state, self.sequence, self.source
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_theme() {
+        assert_eq!("light".parse::<Theme>().unwrap(), Theme::Light);
+        assert_eq!("dark".parse::<Theme>().unwrap(), Theme::Dark);
+        assert_eq!(
+            "no-preference".parse::<Theme>().unwrap(),
+            Theme::NoPreference
+        );
+        assert_eq!(
+            "solarized_dim-2".parse::<Theme>().unwrap(),
+            Theme::Named("solarized_dim-2".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_invalid_theme() {
+        for value in [
+            "",
+            "heartbeat",
+            "dark blue",
+            "../dark",
+            "dark\n",
+            "dunkel\u{e4}",
+        ] {
+            assert!(
+                matches!(value.parse::<Theme>(), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_invalid_theme_state() {
+        // An empty line is what a closed connection reads as
+        for value in ["", "\n", " ", "dark accent-color=blue", "dark contrast=max"] {
+            assert!(
+                matches!(value.parse::<ThemeState>(), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_no_preference() {
+        assert_eq!(Theme::NoPreference.resolve(&Theme::Dark), &Theme::Dark);
+        assert_eq!(Theme::Light.resolve(&Theme::Dark), &Theme::Light);
+    }
+
+    #[test]
+    fn no_preference_policy_from_config() {
+        let _env = crate::lock_env();
+        let env_key = "THEME_TEST_NO_PREFERENCE_THEME";
+        assert_eq!(no_preference_policy(env_key, None).unwrap(), Theme::Light);
+        assert_eq!(
+            no_preference_policy(env_key, Some("dark")).unwrap(),
+            Theme::Dark
+        );
+        assert!(matches!(
+            no_preference_policy(env_key, Some("dim")),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn parse_accent_color() {
+        let color: AccentColor = "#3584E4".parse().unwrap();
+        assert_eq!(
+            color,
+            AccentColor {
+                red: 0x35,
+                green: 0x84,
+                blue: 0xe4
+            }
+        );
+        assert_eq!(color.to_string(), "#3584e4");
+    }
+
+    #[test]
+    fn parse_invalid_accent_color() {
+        for value in [
+            "",
+            "#",
+            "3584e4",
+            "#3584e",
+            "#3584e4f",
+            "#35g4e4",
+            "#35\u{e9}4e",
+        ] {
+            assert!(
+                matches!(value.parse::<AccentColor>(), Err(Error::Parse(_))),
+                "{value:?} parsed"
+            );
+        }
+    }
+
+    #[test]
+    fn accent_color_from_channels() {
+        assert_eq!(
+            AccentColor::from_channels(&[1.0, 0.5, 0.0]),
+            Some(AccentColor {
+                red: 255,
+                green: 128,
+                blue: 0
+            })
+        );
+        assert_eq!(AccentColor::from_channels(&[-1.0, -1.0, -1.0]), None);
+        assert_eq!(AccentColor::from_channels(&[0.5, 0.5]), None);
+    }
+
+    #[test]
+    fn parse_theme_state() {
+        assert_eq!(
+            "dark".parse::<ThemeState>().unwrap(),
+            ThemeState::new(Theme::Dark)
+        );
+
+        let state: ThemeState = "light accent-color=#3584e4 contrast=high future=1"
+            .parse()
+            .unwrap();
+        assert_eq!(state.theme, Theme::Light);
+        assert_eq!(state.accent_color, "#3584e4".parse().ok());
+        assert_eq!(state.contrast, Contrast::High);
+        assert_eq!(
+            state.to_string(),
+            "light accent-color=#3584e4 contrast=high"
+        );
+    }
+
+    #[test]
+    fn parse_contrast() {
+        assert_eq!("normal".parse::<Contrast>().unwrap(), Contrast::Normal);
+        assert_eq!("high".parse::<Contrast>().unwrap(), Contrast::High);
+        assert!(matches!("".parse::<Contrast>(), Err(Error::Parse(_))));
+        assert!(matches!("High".parse::<Contrast>(), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn theme_event_sequence() {
+        let mut event = ThemeEvent::new(ThemeState::new(Theme::Light));
+        assert_eq!((event.sequence, event.source), (1, EventSource::Startup));
+
+        assert!(!event.update(ThemeState::new(Theme::Light), EventSource::Portal));
+        assert_eq!(event.sequence, 1);
+
+        assert!(event.update(ThemeState::new(Theme::Dark), EventSource::Override));
+        assert_eq!((event.sequence, event.source), (2, EventSource::Override));
+        assert!(event
+            .to_string()
+            .starts_with("dark sequence=2 source=override timestamp="));
     }
 }
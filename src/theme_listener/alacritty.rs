@@ -1,28 +1,31 @@
-use super::ThemeListener;
+use super::{ThemeFiles, ThemeListener};
 
 use serde::Deserialize;
-use std::error::Error;
 use std::fs::read_to_string;
 use std::io::Write;
 use std::os::unix::net::UnixStream;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use toml::Table;
 
-use crate::theme::Theme;
+use crate::config::AlacrittyConfig;
+use crate::error::{Error, Result};
+use crate::theme::ThemeState;
 
 #[derive(Deserialize, Debug)]
 struct AlacrittyTheme {
     colors: Table,
 }
 
-fn flatten_table(config: Table) -> Result<Vec<String>, Box<dyn Error>> {
+fn flatten_table(config: Table) -> Result<Vec<String>> {
     let mut result: Vec<String> = vec![];
     let mut keys: Vec<String> = vec!["colors".to_string()];
     let mut config_stack: Vec<&Table> = vec![&config];
 
     while let Some(config_value) = config_stack.pop() {
-        let current_prefix = keys.pop().ok_or("No prefix available")?;
+        let current_prefix = keys
+            .pop()
+            .ok_or_else(|| Error::Config("No prefix available".to_string()))?;
         for entries in config_value {
             let (key, value) = entries;
             // Append it to the stack if value is a table
@@ -43,71 +46,97 @@ fn flatten_table(config: Table) -> Result<Vec<String>, Box<dyn Error>> {
         }
     }
 
-    return Ok(result);
+    Ok(result)
+}
+
+/**
+ * The theme files in the Alacritty theme directory.
+ */
+pub fn theme_files(config: &AlacrittyConfig) -> Result<ThemeFiles> {
+    let alacritty_config_directory = match &config.theme_files.directory {
+        Some(directory) => directory.clone(),
+        None => {
+            let home_directory_env = std::env::var("HOME").unwrap_or(String::from(""));
+            Path::new(&home_directory_env).join(".config/alacritty/themes/")
+        }
+    };
+    ThemeFiles::new(
+        alacritty_config_directory,
+        "ALACRITTY",
+        "toml",
+        &config.theme_files,
+    )
 }
 
 #[derive(Clone)]
 pub struct Alacritty {
-    socket_path: String,
-    light_theme_config_path: String,
-    dark_theme_config_path: String,
+    socket_path: PathBuf,
+    theme_files: ThemeFiles,
+    accent_color_keys: Vec<String>,
 }
 
 impl Alacritty {
-    pub fn new() -> Alacritty {
-        let socket_env = std::env::var("ALACRITTY_SOCKET").unwrap_or(String::from(""));
-
-        let home_directory_env = std::env::var("HOME").unwrap_or(String::from(""));
-        let home_directory_path = Path::new(&home_directory_env);
-
-        let alacritty_config_directory = home_directory_path.join(".config/alacritty/themes/");
-
-        let light_theme =
-            std::env::var("ALACRITTY_LIGHT_THEME").unwrap_or(String::from("Light theme not set"));
-        let dark_theme =
-            std::env::var("ALACRITTY_DARK_THEME").unwrap_or(String::from("Dark theme not set"));
-
-        if light_theme.is_empty() || dark_theme.is_empty() {
-            panic!("Alacritty theme path environment variables not found");
-        }
-
-        let light_theme_config_path =
-            alacritty_config_directory.join(format!("{light_theme}.toml"));
-        let dark_theme_config_path = alacritty_config_directory.join(format!("{dark_theme}.toml"));
-
-        if !light_theme_config_path.exists() || !dark_theme_config_path.exists() {
-            panic!("Alacritty config paths not found");
-        }
-
-        Alacritty {
+    pub fn new(config: &AlacrittyConfig) -> Result<Alacritty> {
+        let socket_env = std::env::var_os("ALACRITTY_SOCKET")
+            .map(PathBuf::from)
+            .or_else(|| config.socket.clone())
+            .unwrap_or_default();
+
+        let theme_files = theme_files(config)?;
+        theme_files.check_built_in()?;
+
+        // Comma separated color keys to override with the accent color,
+        // e.g. "cursor.cursor,selection.background"
+        let accent_color_keys = match std::env::var("ALACRITTY_ACCENT_COLORS") {
+            Ok(keys) => keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(_) => config.accent_colors.clone().unwrap_or_default(),
+        };
+
+        Ok(Alacritty {
             socket_path: socket_env,
-            light_theme_config_path: light_theme_config_path.to_str().unwrap().to_string(),
-            dark_theme_config_path: dark_theme_config_path.to_str().unwrap().to_string(),
-        }
+            theme_files,
+            accent_color_keys,
+        })
     }
 }
 
 impl ThemeListener<usize> for Alacritty {
-    fn handle(self, next_theme_value: Theme) -> std::io::Result<usize> {
-        let theme: AlacrittyTheme;
-        match next_theme_value {
-            Theme::DARK => {
-                let dark_theme_config = read_to_string(self.dark_theme_config_path)?;
-                theme = toml::from_str(&dark_theme_config).unwrap();
-            }
-            Theme::LIGHT => {
-                let light_theme_config = read_to_string(self.light_theme_config_path)?;
-                theme = toml::from_str(&light_theme_config).unwrap();
+    fn handle(self, next_theme_state: ThemeState) -> Result<usize> {
+        let theme_config_path = self.theme_files.path(&next_theme_state)?;
+        let theme_config = read_to_string(&theme_config_path).map_err(|error| {
+            Error::Listener(format!(
+                "Error reading {}: {error}",
+                theme_config_path.display()
+            ))
+        })?;
+        let theme: AlacrittyTheme = toml::from_str(&theme_config).map_err(|error| {
+            Error::Config(format!(
+                "Invalid Alacritty theme {}: {error}",
+                theme_config_path.display()
+            ))
+        })?;
+        let mut options = flatten_table(theme.colors)?;
+        if let Some(accent_color) = next_theme_state.accent_color {
+            for key in &self.accent_color_keys {
+                options.push(format!("\"colors.{}=\\\"{}\\\"\"", key, accent_color));
             }
         }
-        let options = flatten_table(theme.colors).unwrap().join(",");
+        let options = options.join(",");
         let value = format!(
             r#"{{"Config":{{"options": [{}],"reset": false}}}}"#,
             options
         );
 
         // Write JSON
-        let mut connection = UnixStream::connect(self.socket_path.clone())?;
-        connection.write(value.as_bytes())
+        let mut connection = UnixStream::connect(&self.socket_path)
+            .map_err(|error| Error::Ipc("Error connecting to Alacritty".to_string(), error))?;
+        connection
+            .write(value.as_bytes())
+            .map_err(|error| Error::Ipc("Error writing to Alacritty".to_string(), error))
     }
 }
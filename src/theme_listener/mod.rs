@@ -1,4 +1,11 @@
-use crate::theme::Theme;
+use crate::config::{Config, ThemeFilesConfig};
+use crate::error::{Error, Result};
+use crate::theme::{no_preference_policy, Contrast, Theme, ThemeState};
+use alacritty::Alacritty;
+use tmux::Tmux;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
 
 /**
  * Trait for listeners to implement their own custom logic.
@@ -6,12 +13,347 @@ use crate::theme::Theme;
 pub trait ThemeListener<T> {
     /**
      *
-     * @param next_theme_value Gets the applied theme value.
-     * @return The IO call result of the listener.
+     * @param next_theme_state Gets the applied theme and accent color.
+     * @return The result of the listener.
+     */
+    fn handle(self, next_theme_state: ThemeState) -> Result<T>;
+}
+
+/**
+ * Resolves theme variants to files inside a listener's theme directory.
+ * A variant uses the file named by <PREFIX>_<VARIANT>_THEME when set, then
+ * the one configured in themes and <variant>.<extension> otherwise. High
+ * contrast files are read from <PREFIX>_<VARIANT>_HC_THEME or
+ * high_contrast_themes and fall back to the normal file.
+ */
+#[derive(Clone)]
+pub struct ThemeFiles {
+    directory: PathBuf,
+    env_prefix: &'static str,
+    extension: &'static str,
+    no_preference_theme: Theme,
+    themes: HashMap<String, String>,
+    high_contrast_themes: HashMap<String, String>,
+}
+
+impl ThemeFiles {
+    /**
+     * @param directory The directory holding the theme files
+     * @param env_prefix The prefix of the environment variables, e.g. TMUX
+     * @param extension The theme file extension
+     * @param config The theme files set in the config file
+     */
+    pub fn new(
+        directory: PathBuf,
+        env_prefix: &'static str,
+        extension: &'static str,
+        config: &ThemeFilesConfig,
+    ) -> Result<ThemeFiles> {
+        Ok(ThemeFiles {
+            directory,
+            env_prefix,
+            extension,
+            no_preference_theme: no_preference_policy(
+                &format!("{env_prefix}_NO_PREFERENCE_THEME"),
+                config.no_preference.as_deref(),
+            )?,
+            themes: config.themes.clone(),
+            high_contrast_themes: config.high_contrast_themes.clone(),
+        })
+    }
+
+    /**
+     * Checks the built in variants, a listener needs them up front.
+     */
+    pub fn check_built_in(&self) -> Result<()> {
+        for theme in [Theme::Light, Theme::Dark] {
+            for contrast in [Contrast::Normal, Contrast::High] {
+                let mut theme_state = ThemeState::new(theme.clone());
+                theme_state.contrast = contrast;
+                self.path(&theme_state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Finds the theme file for the given state.
+     * @param theme_state The state to apply
      */
-    fn handle(self, next_theme_value: Theme) -> std::io::Result<T>;
+    pub fn path(&self, theme_state: &ThemeState) -> Result<PathBuf> {
+        let variant = theme_state
+            .theme
+            .resolve(&self.no_preference_theme)
+            .to_string();
+        let env_key = format!(
+            "{}_{}",
+            self.env_prefix,
+            variant.to_uppercase().replace('-', "_")
+        );
+
+        let hc_theme = match theme_state.contrast {
+            Contrast::High => std::env::var(format!("{env_key}_HC_THEME"))
+                .ok()
+                .or_else(|| self.high_contrast_themes.get(&variant).cloned()),
+            Contrast::Normal => None,
+        };
+        let theme = hc_theme
+            .or_else(|| std::env::var(format!("{env_key}_THEME")).ok())
+            .or_else(|| self.themes.get(&variant).cloned())
+            .unwrap_or(variant);
+
+        let theme_config_path = self.directory.join(format!("{theme}.{}", self.extension));
+        if !theme_config_path.exists() {
+            return Err(Error::Config(format!(
+                "{} theme {} not found",
+                self.env_prefix,
+                theme_config_path.display()
+            )));
+        }
+        Ok(theme_config_path)
+    }
+}
+
+/**
+ * The named theme variants clients may set, those a listener resolves to a
+ * theme file through its environment variables, its config or a file named
+ * after the variant.
+ */
+#[derive(Clone, Default)]
+pub struct Variants(Vec<ThemeFiles>);
+
+impl Variants {
+    /**
+     * Looks variants up in the theme files of every listener, as they may
+     * run inside the daemon or in a client.
+     * @param config The config file settings
+     */
+    pub fn new(config: &Config) -> Variants {
+        let theme_files = [
+            alacritty::theme_files(&config.alacritty),
+            tmux::theme_files(&config.tmux),
+        ];
+        Variants(theme_files.into_iter().filter_map(Result::ok).collect())
+    }
+
+    /**
+     * Whether a listener has a theme file for the named variant.
+     * @param variant The name of the variant, e.g. dim
+     */
+    pub fn contains(&self, variant: &str) -> bool {
+        let theme_state = ThemeState::new(Theme::Named(variant.to_string()));
+        self.0
+            .iter()
+            .any(|theme_files| theme_files.path(&theme_state).is_ok())
+    }
+}
+
+/**
+ * Names of the listeners that can be enabled, e.g. with -alacritty
+ */
+pub const LISTENER_NAMES: [&str; 2] = ["alacritty", "tmux"];
+
+/**
+ * One of the listeners shipped with theme-listener.
+ */
+#[derive(Clone)]
+pub enum Listener {
+    Alacritty(Alacritty),
+    Tmux(Tmux),
+}
+
+impl Listener {
+    /**
+     * Creates the listener with the given name.
+     * @param name One of LISTENER_NAMES
+     * @param config The config file settings
+     */
+    pub fn new(name: &str, config: &Config) -> Result<Listener> {
+        match name {
+            "alacritty" => Ok(Listener::Alacritty(Alacritty::new(&config.alacritty)?)),
+            "tmux" => Ok(Listener::Tmux(Tmux::new(&config.tmux)?)),
+            _ => Err(Error::Config(format!("Unknown listener {name:?}"))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Listener::Alacritty(_) => "alacritty",
+            Listener::Tmux(_) => "tmux",
+        }
+    }
+}
+
+impl ThemeListener<()> for Listener {
+    fn handle(self, next_theme_state: ThemeState) -> Result<()> {
+        match self {
+            Listener::Alacritty(alacritty) => alacritty.handle(next_theme_state).map(|_| ()),
+            Listener::Tmux(tmux) => tmux.handle(next_theme_state).map(|_| ()),
+        }
+    }
+}
+
+/**
+ * Applies every theme to a set of listeners. A failing listener is only
+ * reported and does not keep the others from applying the theme.
+ */
+#[derive(Clone)]
+pub struct Listeners(Vec<Listener>);
+
+impl Listeners {
+    pub fn new(listeners: Vec<Listener>) -> Listeners {
+        Listeners(listeners)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl ThemeListener<()> for Listeners {
+    fn handle(self, next_theme_state: ThemeState) -> Result<()> {
+        for listener in self.0 {
+            let name = listener.name();
+            if let Err(error) = listener.handle(next_theme_state.clone()) {
+                println!("WARNING: {name} listener: {error}");
+            }
+        }
+        Ok(())
+    }
 }
 
 // Module exports
 pub mod alacritty;
 pub mod tmux;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn theme_directory(name: &str, files: &[&str]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("theme-listener-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        for file in files {
+            std::fs::write(directory.join(format!("{file}.conf")), "").unwrap();
+        }
+        directory
+    }
+
+    fn state(theme: Theme, contrast: Contrast) -> ThemeState {
+        let mut state = ThemeState::new(theme);
+        state.contrast = contrast;
+        state
+    }
+
+    #[test]
+    fn theme_files_from_config() {
+        let _env = crate::lock_env();
+        let directory = theme_directory(
+            "config",
+            &["light", "dark-default", "dark-hc", "dim", "sepia"],
+        );
+        let config = ThemeFilesConfig {
+            no_preference: Some("dark".to_string()),
+            themes: HashMap::from([
+                ("dark".to_string(), "dark-default".to_string()),
+                ("dim".to_string(), "dim".to_string()),
+            ]),
+            high_contrast_themes: HashMap::from([("dark".to_string(), "dark-hc".to_string())]),
+            ..ThemeFilesConfig::default()
+        };
+        let theme_files =
+            ThemeFiles::new(directory.clone(), "THEME_FILES_CONFIG", "conf", &config).unwrap();
+        let path = |theme, contrast| theme_files.path(&state(theme, contrast)).unwrap();
+
+        assert_eq!(
+            path(Theme::Light, Contrast::High),
+            directory.join("light.conf")
+        );
+        assert_eq!(
+            path(Theme::Dark, Contrast::Normal),
+            directory.join("dark-default.conf")
+        );
+        assert_eq!(
+            path(Theme::Dark, Contrast::High),
+            directory.join("dark-hc.conf")
+        );
+        assert_eq!(
+            path(Theme::NoPreference, Contrast::Normal),
+            directory.join("dark-default.conf")
+        );
+        // Undeclared named themes use the file named after them
+        assert_eq!(
+            path(Theme::Named("sepia".to_string()), Contrast::High),
+            directory.join("sepia.conf")
+        );
+        assert!(matches!(
+            theme_files.path(&state(Theme::Named("drak".to_string()), Contrast::Normal)),
+            Err(Error::Config(_))
+        ));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn theme_files_missing_built_in_variant() {
+        let _env = crate::lock_env();
+        let directory = theme_directory("missing", &["light"]);
+        assert!(matches!(
+            ThemeFiles::new(
+                directory.clone(),
+                "THEME_FILES_MISSING",
+                "conf",
+                &ThemeFilesConfig::default()
+            )
+            .unwrap()
+            .check_built_in(),
+            Err(Error::Config(_))
+        ));
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn theme_files_environment_wins() {
+        let _env = crate::lock_env();
+        let directory = theme_directory("env", &["light", "dark", "dim", "dim-env"]);
+        let config = ThemeFilesConfig {
+            themes: HashMap::from([("dim".to_string(), "dim".to_string())]),
+            ..ThemeFilesConfig::default()
+        };
+        std::env::set_var("THEME_FILES_ENV_DIM_THEME", "dim-env");
+        let theme_files =
+            ThemeFiles::new(directory.clone(), "THEME_FILES_ENV", "conf", &config).unwrap();
+        assert_eq!(
+            theme_files
+                .path(&state(Theme::Named("dim".to_string()), Contrast::Normal))
+                .unwrap(),
+            directory.join("dim-env.conf")
+        );
+        std::env::remove_var("THEME_FILES_ENV_DIM_THEME");
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn variants_with_theme_files() {
+        let _env = crate::lock_env();
+        let directory = theme_directory("variants", &[]);
+        std::fs::write(directory.join("dim.config"), "").unwrap();
+        std::fs::write(directory.join("presentation.config"), "").unwrap();
+        let mut config = Config::default();
+        config.alacritty.theme_files.directory = Some(directory.join("missing"));
+        config.tmux.theme_files.directory = Some(directory.clone());
+        config.tmux.theme_files.themes =
+            HashMap::from([("sepia".to_string(), "missing".to_string())]);
+        let variants = Variants::new(&config);
+
+        assert!(variants.contains("dim"));
+        assert!(!variants.contains("drak"));
+        // Declared without a file to apply
+        assert!(!variants.contains("sepia"));
+        std::env::set_var("TMUX_SEPIA_THEME", "presentation");
+        assert!(variants.contains("sepia"));
+        std::env::remove_var("TMUX_SEPIA_THEME");
+        let _ = std::fs::remove_dir_all(directory);
+    }
+}
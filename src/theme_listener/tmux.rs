@@ -1,62 +1,79 @@
-use super::ThemeListener;
+use super::{ThemeFiles, ThemeListener};
 
-use std::io::Result;
+use std::ffi::OsStr;
 use std::path::Path;
 use std::process::{Command, Output};
 
-use crate::theme::Theme;
+use crate::config::TmuxConfig;
+use crate::error::{Error, Result};
+use crate::theme::ThemeState;
 
-#[derive(Clone)]
-pub struct Tmux {
-    light_theme_config_path: String,
-    dark_theme_config_path: String,
+/**
+ * Runs a tmux command, turning a failed spawn or a non-zero exit status into
+ * a listener error.
+ */
+fn run_tmux<S: AsRef<OsStr>>(args: &[S]) -> Result<Output> {
+    let output = Command::new("tmux")
+        .args(args)
+        .output()
+        .map_err(|error| Error::Listener(format!("Error running tmux: {error}")))?;
+    if !output.status.success() {
+        return Err(Error::Listener(format!(
+            "tmux exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output)
 }
 
-impl Tmux {
-    pub fn new() -> Tmux {
-        let home_directory_env = std::env::var("HOME").unwrap_or(String::from(""));
-        let home_directory_path = Path::new(&home_directory_env);
-        let tmux_config_directory = home_directory_path.join(".config/tmux/themes/");
-        if !home_directory_path.exists() {
-            panic!("Home directory path is invalid");
-        }
-
-        if !tmux_config_directory.exists() {
-            panic!("TMUX config directory path not found");
+/**
+ * The theme files in the tmux theme directory.
+ */
+pub fn theme_files(config: &TmuxConfig) -> Result<ThemeFiles> {
+    let tmux_config_directory = match &config.theme_files.directory {
+        Some(directory) => directory.clone(),
+        None => {
+            let home_directory_env = std::env::var("HOME").unwrap_or(String::from(""));
+            let home_directory_path = Path::new(&home_directory_env);
+            if !home_directory_path.exists() {
+                return Err(Error::Config("Home directory path is invalid".to_string()));
+            }
+            home_directory_path.join(".config/tmux/themes/")
         }
+    };
 
-        let light_theme =
-            std::env::var("TMUX_LIGHT_THEME").unwrap_or(String::from("Light theme not set"));
-        let dark_theme =
-            std::env::var("TMUX_DARK_THEME").unwrap_or(String::from("Dark theme not set"));
+    if !tmux_config_directory.exists() {
+        return Err(Error::Config(
+            "TMUX config directory path not found".to_string(),
+        ));
+    }
 
-        let light_theme_config_path = tmux_config_directory.join(format!("{light_theme}.config"));
-        let dark_theme_config_path = tmux_config_directory.join(format!("{dark_theme}.config"));
+    ThemeFiles::new(tmux_config_directory, "TMUX", "config", &config.theme_files)
+}
 
-        if !light_theme_config_path.exists() || !dark_theme_config_path.exists() {
-            panic!("Config paths not found");
-        }
+#[derive(Clone)]
+pub struct Tmux {
+    theme_files: ThemeFiles,
+}
 
-        Tmux {
-            light_theme_config_path: light_theme_config_path.to_str().unwrap().to_string(),
-            dark_theme_config_path: dark_theme_config_path.to_str().unwrap().to_string(),
-        }
+impl Tmux {
+    pub fn new(config: &TmuxConfig) -> Result<Tmux> {
+        let theme_files = theme_files(config)?;
+        theme_files.check_built_in()?;
+        Ok(Tmux { theme_files })
     }
 }
 
 impl ThemeListener<Output> for Tmux {
-    fn handle(self, next_theme_value: Theme) -> Result<Output> {
-        let selected_theme: &str;
-        match next_theme_value {
-            Theme::DARK => {
-                selected_theme = &self.dark_theme_config_path;
-            }
-            Theme::LIGHT => {
-                selected_theme = &self.light_theme_config_path;
-            }
+    fn handle(self, next_theme_state: ThemeState) -> Result<Output> {
+        // Expose the accent color as a user option before sourcing the theme
+        // so theme files can refer to #{@accent-color}
+        if let Some(accent_color) = next_theme_state.accent_color {
+            run_tmux(&["set", "-g", "@accent-color", &accent_color.to_string()])?;
         }
-        Command::new("tmux")
-            .args(["source", selected_theme])
-            .output()
+
+        let selected_theme = self.theme_files.path(&next_theme_state)?;
+        run_tmux(&["source".as_ref(), selected_theme.as_os_str()])
     }
 }
@@ -1,14 +1,15 @@
 use super::ThemePublisher;
 
-use std::error::Error;
+use std::sync::Arc;
 use std::time::Duration;
 
 // DBus
-use dbus::arg::{RefArg, Variant};
+use dbus::arg::{ArgType, RefArg, Variant};
 use dbus::blocking::{Connection, Proxy};
 use dbus::{arg, Message};
 
-use crate::theme::Theme;
+use crate::error::Result;
+use crate::theme::{AccentColor, Contrast, Theme, ThemeState, ThemeUpdate};
 
 struct OrgFreeDesktopPortalDesktop {
     pub sender: String,
@@ -22,7 +23,7 @@ impl arg::AppendAll for OrgFreeDesktopPortalDesktop {
 }
 
 impl arg::ReadAll for OrgFreeDesktopPortalDesktop {
-    fn read(i: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+    fn read(i: &mut arg::Iter) -> std::result::Result<Self, arg::TypeMismatchError> {
         Ok(OrgFreeDesktopPortalDesktop {
             sender: i.read()?,
             key: i.read()?,
@@ -36,6 +37,31 @@ impl dbus::message::SignalArgs for OrgFreeDesktopPortalDesktop {
     const INTERFACE: &'static str = "org.freedesktop.portal.Settings";
 }
 
+/**
+ * Reads the portal accent-color value, an (ddd) struct that may be wrapped in
+ * one or more variants.
+ */
+fn read_accent_color(value: &dyn RefArg) -> Option<AccentColor> {
+    match value.arg_type() {
+        ArgType::Variant => read_accent_color(value.as_iter()?.next()?),
+        ArgType::Struct => {
+            let channels: Vec<f64> = value.as_iter()?.filter_map(|c| c.as_f64()).collect();
+            AccentColor::from_channels(&channels)
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Maps the portal contrast value, where 1 is high contrast.
+ */
+fn read_contrast(value: &dyn RefArg) -> Contrast {
+    match value.as_i64() {
+        Some(1) => Contrast::High,
+        _ => Contrast::Normal,
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct DBusPublisher;
 
@@ -46,7 +72,7 @@ impl DBusPublisher {
 }
 
 impl ThemePublisher<i64> for DBusPublisher {
-    fn fetch(self) -> Result<Theme, Box<dyn Error>> {
+    fn fetch(self) -> Result<ThemeState> {
         let conn = Connection::new_session()?;
         let proxy = Proxy::new(
             "org.freedesktop.portal.Desktop",
@@ -59,42 +85,123 @@ impl ThemePublisher<i64> for DBusPublisher {
             "Read",
             ("org.freedesktop.appearance", "color-scheme"),
         )?;
-        let mut theme: Theme = Theme::DARK;
+        let mut theme: Theme = Theme::Dark;
 
         if let Some(theme_value) = result.0 .0.as_i64() {
             theme = self.to_theme(theme_value);
         }
-        Ok(theme)
+
+        // Older portals do not expose accent color or contrast
+        let accent_color: std::result::Result<(Variant<Box<dyn RefArg>>,), dbus::Error> = proxy
+            .method_call(
+                "org.freedesktop.portal.Settings",
+                "Read",
+                ("org.freedesktop.appearance", "accent-color"),
+            );
+
+        let mut state = ThemeState::new(theme);
+        if let Ok(accent_color) = accent_color {
+            state.accent_color = read_accent_color(&accent_color.0);
+        }
+
+        let contrast: std::result::Result<(Variant<Box<dyn RefArg>>,), dbus::Error> = proxy
+            .method_call(
+                "org.freedesktop.portal.Settings",
+                "Read",
+                ("org.freedesktop.appearance", "contrast"),
+            );
+        if let Ok(contrast) = contrast {
+            state.contrast = read_contrast(&contrast.0);
+        }
+        Ok(state)
     }
 
-    fn on_publish(self, callback: Box<dyn Fn(Theme) + Send>) {
-        let connection = Connection::new_session().unwrap();
+    fn on_publish(self, callback: Box<dyn Fn(ThemeUpdate) + Send + Sync>) -> Result<()> {
+        let callback: Arc<dyn Fn(ThemeUpdate) + Send + Sync> = Arc::from(callback);
+        let signal_callback = Arc::clone(&callback);
+        let connection = Connection::new_session()?;
         let proxy = connection.with_proxy(
             "org.freedesktop.portal.Desktop",
             "/org/freedesktop/portal/desktop",
             Duration::from_millis(5000),
         );
 
-        let _ = proxy.match_signal(
+        proxy.match_signal(
             move |h: OrgFreeDesktopPortalDesktop, _: &Connection, _: &Message| {
-                if h.sender == "org.freedesktop.appearance" && h.key == "color-scheme" {
-                    let next_theme_value = h.value.as_i64().unwrap();
-                    let next_theme = self.to_theme(next_theme_value);
-                    callback(next_theme);
+                if h.sender != "org.freedesktop.appearance" {
+                    return true;
+                }
+                match h.key.as_str() {
+                    "color-scheme" => {
+                        if let Some(next_theme_value) = h.value.as_i64() {
+                            let next_theme = self.to_theme(next_theme_value);
+                            signal_callback(ThemeUpdate::Theme(next_theme));
+                        }
+                    }
+                    "accent-color" => {
+                        signal_callback(ThemeUpdate::AccentColor(read_accent_color(&h.value)));
+                    }
+                    "contrast" => {
+                        signal_callback(ThemeUpdate::Contrast(read_contrast(&h.value)));
+                    }
+                    _ => {}
                 }
                 true
             },
-        );
+        )?;
+
+        // Changes between the daemon fetching the theme and the match above
+        // were never signalled
+        callback(ThemeUpdate::State(self.fetch()?));
 
         loop {
-            connection.process(Duration::from_millis(1000)).unwrap();
+            connection.process(Duration::from_millis(1000))?;
         }
     }
 
+    /**
+     * Maps the portal color-scheme value, where 0 is no preference, 1 is
+     * prefer dark and 2 is prefer light.
+     */
     fn to_theme(self, value: i64) -> Theme {
-        if value == 1 {
-            return Theme::DARK;
+        match value {
+            1 => Theme::Dark,
+            2 => Theme::Light,
+            _ => Theme::NoPreference,
         }
-        return Theme::LIGHT;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portal_color_scheme() {
+        let publisher = DBusPublisher::new();
+        assert_eq!(publisher.to_theme(0), Theme::NoPreference);
+        assert_eq!(publisher.to_theme(1), Theme::Dark);
+        assert_eq!(publisher.to_theme(2), Theme::Light);
+        assert_eq!(publisher.to_theme(3), Theme::NoPreference);
+    }
+
+    #[test]
+    fn portal_accent_color() {
+        let accent_color = Variant(Box::new((0.2f64, 0.5f64, 1.0f64)) as Box<dyn RefArg>);
+        assert_eq!(
+            read_accent_color(&accent_color),
+            "#3380ff".parse::<AccentColor>().ok()
+        );
+        // Out of range channels mean the user did not pick an accent color
+        let unset = (-1.0f64, -1.0f64, -1.0f64);
+        assert_eq!(read_accent_color(&unset), None);
+        assert_eq!(read_accent_color(&1u32), None);
+    }
+
+    #[test]
+    fn portal_contrast() {
+        assert_eq!(read_contrast(&1u32), Contrast::High);
+        assert_eq!(read_contrast(&0u32), Contrast::Normal);
+        assert_eq!(read_contrast(&"high".to_string()), Contrast::Normal);
     }
 }
@@ -1,6 +1,7 @@
 use super::ThemePublisher;
 
-use crate::theme::Theme;
+use crate::error::{Error, Result};
+use crate::theme::{Contrast, Theme, ThemeState, ThemeUpdate};
 use objc2::{define_class, extern_methods, msg_send, AllocAnyThread, DefinedClass};
 use objc2_app_kit::{NSAppearance, NSApplication};
 use objc2_foundation::{
@@ -57,6 +58,19 @@ define_class!(
     unsafe impl NSObjectProtocol for ThemeObserver {}
 );
 
+/**
+ * High contrast appearances carry "HighContrast" in their name, e.g.
+ * NSAppearanceNameAccessibilityHighContrastDarkAqua.
+ */
+fn to_contrast(theme_value: &NSString) -> Contrast {
+    unsafe {
+        if theme_value.containsString(ns_string!("HighContrast")) {
+            return Contrast::High;
+        }
+    }
+    Contrast::Normal
+}
+
 #[derive(Copy, Clone)]
 pub struct KVOPublisher;
 
@@ -65,44 +79,58 @@ impl KVOPublisher {
         // Create a theme observer class
         KVOPublisher {}
     }
+
+    /**
+     * Maps an appearance name to the whole theme state, as the name carries
+     * both the theme and the contrast.
+     */
+    fn to_state(self, theme_value: Retained<NSString>) -> ThemeState {
+        let contrast = to_contrast(&theme_value);
+        let mut state = ThemeState::new(self.to_theme(theme_value));
+        state.contrast = contrast;
+        state
+    }
 }
 
 impl ThemePublisher<Retained<NSString>> for KVOPublisher {
-    fn fetch(self) -> Result<Theme, Box<dyn std::error::Error>> {
+    fn fetch(self) -> Result<ThemeState> {
         unsafe {
-            let appearance = NSAppearance::currentAppearance().unwrap();
-            let theme_value = appearance.name();
-            let theme: Theme = self.to_theme(theme_value);
-            Ok(theme)
+            let appearance = NSAppearance::currentAppearance()
+                .ok_or_else(|| Error::AppKit("No current appearance".to_string()))?;
+            Ok(self.to_state(appearance.name()))
         }
     }
 
-    fn on_publish(self, callback: Box<dyn Fn(Theme) + Send + 'static>) {
-        let mtm = MainThreadMarker::new().unwrap();
+    fn on_publish(self, callback: Box<dyn Fn(ThemeUpdate) + Send + Sync>) -> Result<()> {
+        let mtm = MainThreadMarker::new()
+            .ok_or_else(|| Error::AppKit("Publisher must run on the main thread".to_string()))?;
         let app = NSApplication::sharedApplication(mtm);
+        // One update per change, subscribers never see the new contrast
+        // with the old theme
         let observer = ThemeObserver::new(move |next_theme_value: Retained<NSString>| {
-            let next_theme = self.to_theme(next_theme_value);
-            callback(next_theme);
+            callback(ThemeUpdate::State(self.to_state(next_theme_value)));
         });
 
-        // Register app observer key path
+        // Register app observer key path, Initial reports the current value
+        // so a change since the daemon fetched the theme is not lost
         unsafe {
             app.addObserver_forKeyPath_options_context(
                 &observer,
                 ns_string!("effectiveAppearance"),
-                NSKeyValueObservingOptions::New,
+                NSKeyValueObservingOptions::New | NSKeyValueObservingOptions::Initial,
                 ptr::null_mut(),
             );
         }
         app.run();
+        Ok(())
     }
 
     fn to_theme(self, theme_value: Retained<NSString>) -> Theme {
         unsafe {
             if theme_value.containsString(ns_string!("Dark")) {
-                return Theme::DARK;
+                return Theme::Dark;
             }
         }
-        return Theme::LIGHT;
+        Theme::Light
     }
 }
@@ -1,16 +1,18 @@
-use crate::theme::Theme;
-use std::error::Error;
+use crate::error::Result;
+use crate::theme::{Theme, ThemeState, ThemeUpdate};
 
 cfg_if::cfg_if!(
     if #[cfg(target_os = "linux")] {
         mod linux;
         use crate::theme_publisher::linux::DBusPublisher;
+        pub const PUBLISHER_NAME: &str = "portal";
         pub fn create_publisher() -> DBusPublisher {
             DBusPublisher::new()
         }
     } else if #[cfg(target_os = "macos")] {
         mod macos;
         use crate::theme_publisher::macos::KVOPublisher;
+        pub const PUBLISHER_NAME: &str = "appearance";
         pub fn create_publisher() -> KVOPublisher {
             KVOPublisher::new()
         }
@@ -23,15 +25,16 @@ cfg_if::cfg_if!(
  */
 pub trait ThemePublisher<T> {
     /**
-     * Fetches the current theme value
+     * Fetches the current theme state
      */
-    fn fetch(self) -> Result<Theme, Box<dyn Error>>;
+    fn fetch(self) -> Result<ThemeState>;
 
     /**
      * A function to trigger on theme change
-     * @param callback function to be called on theme change
+     * @param callback function to be called with each changed setting
+     * @return Only returns when the publisher fails
      */
-    fn on_publish(self, callback: Box<dyn Fn(Theme) + Send>);
+    fn on_publish(self, callback: Box<dyn Fn(ThemeUpdate) + Send + Sync>) -> Result<()>;
 
     /**
      * Method to convert publisher value to Theme